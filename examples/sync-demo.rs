@@ -3,26 +3,26 @@ use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::ops::BitOr;
 use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::sync::Arc;
+use std::time::Duration;
 
 use ::fanotify::{prelude::*, bitflags};
 use clap::Parser;
 use log::*;
-use nix::sys::signal::{SaFlags, SigAction, SigSet, Signal};
+use nix::sys::signal::{SigSet, Signal};
 
 #[derive(thiserror::Error, Debug)]
 enum Error {
     #[error("io error: {0}")]
     IOError(#[from] std::io::Error),
 
+    #[error("fanotify error: {0}")]
+    Fanotify(#[from] fanotify::error::FanotifyError),
+
     #[error("os error: {0}")]
     NixErrno(#[from] nix::Error)
 }
 
-extern "C" fn interrupt_handler(_: i32) {
-    info!("exiting");
-    std::process::exit(0);
-}
-
 #[derive(Debug, clap::Parser)]
 #[clap(
     about = "fanotify demo",
@@ -129,22 +129,30 @@ fn main() -> Result<(), Error> {
     );
     info!("mask flag: {:x} {:?}", mask_flags.bits(), mask_flags);
 
-    let mut fan = Fanotify::<OwnedFd>::init(init_flags, event_f_flags)?;
+    // blocked before any thread is spawned, so only the signal thread below receives it
+    let mut interrupt = SigSet::empty();
+    interrupt.add(Signal::SIGINT);
+    interrupt.thread_block()?;
+
+    let shutdown = ShutdownHandle::new()?;
+    let resolver = Arc::new(HandleResolver::new());
+    let mut fan = Fanotify::<OwnedFd>::init(init_flags, event_f_flags)?
+        .with_handle_resolver(resolver.clone())
+        .with_shutdown_handle(shutdown.clone());
     for path in args.path {
         debug!("marking path: {path}");
         fan.mark(MarkFlags::FAN_MARK_ADD, mask_flags, None, Some(&path))?;
         info!("path marked: {path}");
     }
-    unsafe {
-        nix::sys::signal::sigaction(
-            nix::sys::signal::SIGINT,
-            &SigAction::new(
-                nix::sys::signal::SigHandler::Handler(interrupt_handler),
-                SaFlags::SA_RESETHAND,
-                SigSet::from(Signal::SIGINT),
-            ),
-        )?
-    };
+    {
+        let shutdown = shutdown.clone();
+        std::thread::spawn(move || {
+            if interrupt.wait().is_ok() {
+                info!("exiting");
+                shutdown.shutdown();
+            }
+        });
+    }
     info!("interrupt handler is set");
 
     let whitelist = args.whitelist;
@@ -153,13 +161,8 @@ fn main() -> Result<(), Error> {
     let mut ready = HashSet::new();
     let mut bufferdfds: HashMap<std::path::PathBuf, Vec<OwnedFd>> = HashMap::new();
     let mut arg0map = HashMap::new();
-    loop {
-        let mut events = fan.read_events()?;
-        if events.len() == 0 {
-            assert!(init_flags & InitFlags::FAN_NONBLOCK == InitFlags::FAN_NONBLOCK);
-            std::thread::sleep(std::time::Duration::from_millis(100));
-            continue;
-        }
+    while !shutdown.is_shutdown() {
+        let mut events = fan.read_events_timeout(Duration::from_secs(1))?;
         for event in events.iter_mut() {
             if event.mask().is_permission_event() {
                 // permission event
@@ -167,7 +170,12 @@ fn main() -> Result<(), Error> {
                     if init_flags & (InitFlags::FAN_REPORT_FID | InitFlags::FAN_REPORT_DIR_FID)
                         != InitFlags::empty()
                     {
-                        warn!("fid not implementd");
+                        for handle in event.event_info.iter().filter_map(EventInfo::file_handle) {
+                            match resolver.resolve_path(handle) {
+                                Ok(path) => warn!("permission event without fd: {:?}", path),
+                                Err(err) => warn!("failed to resolve file handle: {}", err),
+                            }
+                        }
                     } else {
                         warn!("queue full");
                     }
@@ -287,7 +295,17 @@ fn main() -> Result<(), Error> {
                     event.mask(),
                     event.event_info,
                 );
+                for event_info in event.event_info.iter() {
+                    let Some(handle) = event_info.file_handle() else {
+                        continue;
+                    };
+                    match resolver.resolve_path(handle) {
+                        Ok(path) => trace!(" - {:?} {:?}", path, event_info.name()),
+                        Err(err) => warn!("failed to resolve file handle: {}", err),
+                    }
+                }
             }
         }
     }
+    Ok(())
 }
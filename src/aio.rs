@@ -1,69 +1,230 @@
-use std::os::fd::{FromRawFd, OwnedFd};
+use std::{
+    borrow::Borrow,
+    collections::VecDeque,
+    io::Read,
+    os::fd::OwnedFd,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
 
+use futures_core::Stream;
 use tokio::io::unix::AsyncFd;
 
 #[cfg(feature = "aio-async-read-write")]
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::io::Write;
+#[cfg(feature = "aio-async-read-write")]
+use tokio::io::{AsyncRead, AsyncWrite};
 
-#[cfg(not(feature = "aio-async-read-write"))]
 use tokio::io::Interest;
 
 use crate::{
     consts::{EventFFlags, InitFlags},
+    error::FanotifyError,
     fanotify::Fanotify,
-    messages::{Event, Response},
+    messages::{Event, EventBuffer, EventIter, Response},
 };
 
 impl Fanotify<AsyncFd<Fanotify<OwnedFd>>> {
-    pub fn init(init_flags: InitFlags, event_fd_flags: EventFFlags) -> std::io::Result<Self> {
+    pub fn init(init_flags: InitFlags, event_fd_flags: EventFFlags) -> Result<Self, FanotifyError> {
         Self::try_init(init_flags, event_fd_flags)
     }
 
-    pub fn try_init(init_flags: InitFlags, event_fd_flags: EventFFlags) -> std::io::Result<Self> {
-        // add non block flag to make it work with tokio. or else it will block after first read.
-        let init_flags = init_flags | InitFlags::FAN_NONBLOCK;
-        let fd = unsafe {
-            let ret = libc::fanotify_init(init_flags.bits(), event_fd_flags.bits());
-            if ret == -1 {
-                return Err(std::io::Error::last_os_error());
-            }
-            OwnedFd::from_raw_fd(ret)
-        };
-        let fan = AsyncFd::new(Fanotify { fd })?;
-        Ok(Self { fd: fan })
+    /// `FAN_NONBLOCK` is always added, or tokio would block after the first read.
+    pub fn try_init(
+        init_flags: InitFlags,
+        event_fd_flags: EventFFlags,
+    ) -> Result<Self, FanotifyError> {
+        Fanotify::<OwnedFd>::init(init_flags | InitFlags::FAN_NONBLOCK, event_fd_flags)?
+            .into_wrapped(AsyncFd::new)
     }
 
-    pub async fn read_events(&mut self) -> std::io::Result<Vec<Event>> {
-        #[cfg(feature = "aio-async-read-write")]
-        {
-            const BUFFER_SIZE: usize = 4096;
-            let mut buffer = [0u8; BUFFER_SIZE];
-            let nread = self.read(&mut buffer).await?;
-            Ok(Event::extract_from(&buffer[0..nread]))
-        }
+    pub fn init_unprivileged(
+        init_flags: InitFlags,
+        event_fd_flags: EventFFlags,
+    ) -> Result<Self, FanotifyError> {
+        Fanotify::<OwnedFd>::init_unprivileged(
+            init_flags | InitFlags::FAN_NONBLOCK,
+            event_fd_flags,
+        )?
+        .into_wrapped(AsyncFd::new)
+    }
+
+    pub fn init_fs_error_monitor<P: AsRef<std::path::Path>>(
+        path: P,
+    ) -> Result<Self, FanotifyError> {
+        Fanotify::<OwnedFd>::init_fs_error_monitor_with(path, InitFlags::FAN_NONBLOCK)?
+            .into_wrapped(AsyncFd::new)
+    }
 
-        #[cfg(not(feature = "aio-async-read-write"))]
+    pub async fn read_events(&mut self) -> std::io::Result<Vec<Event>> {
         self.fd
-            .async_io_mut(Interest::READABLE, |r| r.read_events())
+            .async_io(Interest::READABLE, Fanotify::read_events_once)
             .await
     }
 
+    pub async fn read_into<'a>(
+        &mut self,
+        buffer: &'a mut EventBuffer,
+    ) -> std::io::Result<EventIter<'a>> {
+        let nread = self
+            .fd
+            .async_io(Interest::READABLE, |mut r| r.read(buffer.as_mut_slice()))
+            .await?;
+        Ok(buffer.events(nread))
+    }
+
+    /// Never waits, see [`Fanotify::write_response`].
     pub async fn write_response(&mut self, response: Response) -> std::io::Result<usize> {
-        #[cfg(feature = "aio-async-read-write")]
-        return self
-            .write(unsafe {
-                std::slice::from_raw_parts(
-                    (&response.inner as *const libc::fanotify_response).cast(),
-                    size_of::<libc::fanotify_response>(),
-                )
-            })
-            .await;
-
-        #[cfg(not(feature = "aio-async-read-write"))]
-        self.fd
-            .async_io_mut(Interest::WRITABLE, |r| r.write_response(response))
+        self.fd.get_ref().respond(&response)
+    }
+
+    /// Stream of the events of this group, borrowing it. Events already read but not yet
+    /// yielded are lost when the stream is dropped, their fds are closed.
+    pub fn events(&mut self) -> EventStream<&mut Self> {
+        EventStream::new(self)
+    }
+
+    pub fn into_stream(self) -> EventStream<Self> {
+        EventStream::new(self)
+    }
+
+    /// Splits the group into a reader and a responder sharing the same fd, so permission
+    /// events can be answered from other tasks while the next ones are read.
+    pub fn split(self) -> (FanotifyReader, FanotifyResponder) {
+        let fan = Arc::new(self);
+        (
+            FanotifyReader { fan: fan.clone() },
+            FanotifyResponder { fan },
+        )
+    }
+}
+
+/// Reading half of [`Fanotify::split`].
+pub struct FanotifyReader {
+    fan: Arc<Fanotify<AsyncFd<Fanotify<OwnedFd>>>>,
+}
+
+impl FanotifyReader {
+    /// The group shared with the [`FanotifyResponder`], marks added here affect both halves.
+    pub fn get_ref(&self) -> &Fanotify<AsyncFd<Fanotify<OwnedFd>>> {
+        &self.fan
+    }
+
+    pub async fn read_events(&mut self) -> std::io::Result<Vec<Event>> {
+        self.fan
+            .fd
+            .async_io(Interest::READABLE, Fanotify::read_events_once)
             .await
     }
+
+    pub async fn read_into<'a>(
+        &mut self,
+        buffer: &'a mut EventBuffer,
+    ) -> std::io::Result<EventIter<'a>> {
+        let nread = self
+            .fan
+            .fd
+            .async_io(Interest::READABLE, |mut r| r.read(buffer.as_mut_slice()))
+            .await?;
+        Ok(buffer.events(nread))
+    }
+
+    pub fn events(&mut self) -> EventStream<&Fanotify<AsyncFd<Fanotify<OwnedFd>>>> {
+        EventStream::new(&self.fan)
+    }
+
+    pub fn into_stream(self) -> EventStream<Arc<Fanotify<AsyncFd<Fanotify<OwnedFd>>>>> {
+        EventStream::new(self.fan)
+    }
+}
+
+/// Writing half of [`Fanotify::split`], cloned for every task answering permission events.
+#[derive(Clone)]
+pub struct FanotifyResponder {
+    fan: Arc<Fanotify<AsyncFd<Fanotify<OwnedFd>>>>,
+}
+
+impl FanotifyResponder {
+    pub fn get_ref(&self) -> &Fanotify<AsyncFd<Fanotify<OwnedFd>>> {
+        &self.fan
+    }
+
+    /// Never waits, see [`Fanotify::write_response`].
+    pub async fn write_response(&self, response: Response) -> std::io::Result<usize> {
+        self.fan.fd.get_ref().respond(&response)
+    }
+}
+
+/// Events of an async group, one at a time, created by [`Fanotify::events`] or
+/// [`Fanotify::into_stream`].
+///
+/// A read returns as many events as fit into the buffer, the ones not yielded yet are kept
+/// until the next poll, so the group is only read again once they are all taken.
+/// A malformed record is yielded as an error after the events of its read, which are kept
+/// as [`Event::extract_all`] does, so they can still be answered.
+pub struct EventStream<T> {
+    fan: T,
+    buffer: EventBuffer,
+    pending: VecDeque<std::io::Result<Event>>,
+}
+
+impl<T> EventStream<T> {
+    fn new(fan: T) -> Self {
+        Self {
+            fan,
+            buffer: EventBuffer::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.fan
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.fan
+    }
+
+    /// Gives back the group, dropping the events not yielded yet.
+    pub fn into_inner(self) -> T {
+        self.fan
+    }
+}
+
+impl<T> Stream for EventStream<T>
+where
+    T: Borrow<Fanotify<AsyncFd<Fanotify<OwnedFd>>>> + Unpin,
+{
+    type Item = std::io::Result<Event>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(event) = this.pending.pop_front() {
+                return Poll::Ready(Some(event));
+            }
+
+            let fan = this.fan.borrow();
+            let mut guard = ready!(fan.fd.poll_read_ready(cx))?;
+            // try_io clears the readiness only on EAGAIN, so a wakeup is never missed
+            let nread = match guard.try_io(|inner| inner.get_ref().read(this.buffer.as_mut_slice()))
+            {
+                Ok(result) => result?,
+                Err(_would_block) => continue,
+            };
+            if nread == 0 {
+                return Poll::Ready(None);
+            }
+            let (events, error) = Event::extract_all(&this.buffer.as_mut_slice()[..nread]);
+            this.pending.extend(events.into_iter().map(Ok));
+            this.pending.extend(error.map(|error| Err(error.into())));
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.pending.len(), None)
+    }
 }
 
 #[cfg(feature = "aio-async-read-write")]
@@ -89,16 +250,11 @@ impl AsyncRead for Fanotify<AsyncFd<Fanotify<OwnedFd>>> {
 impl AsyncWrite for Fanotify<AsyncFd<Fanotify<OwnedFd>>> {
     fn poll_write(
         self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
+        _: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<Result<usize, std::io::Error>> {
-        let mut guard = match self.get_mut().fd.poll_write_ready_mut(cx) {
-            std::task::Poll::Ready(guard_result) => guard_result,
-            std::task::Poll::Pending => return std::task::Poll::Pending,
-        }?;
-        let nwrite = std::io::Write::write(guard.get_inner_mut(), &buf)?;
-        guard.clear_ready();
-        std::task::Poll::Ready(Ok(nwrite))
+        // like write_response, without waiting for writability
+        std::task::Poll::Ready(self.fd.get_ref().write(buf))
     }
 
     fn poll_flush(
@@ -117,3 +273,87 @@ impl AsyncWrite for Fanotify<AsyncFd<Fanotify<OwnedFd>>> {
         std::task::Poll::Ready(Ok(()))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::Write,
+        os::{
+            fd::{AsRawFd, IntoRawFd, OwnedFd, RawFd},
+            unix::net::UnixStream,
+        },
+        pin::Pin,
+    };
+
+    use futures_core::Stream;
+    use tokio::io::unix::AsyncFd;
+
+    use super::{EventStream, FanotifyReader, FanotifyResponder};
+    use crate::{consts::InitFlags, fanotify::Fanotify};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_split_is_send_sync() {
+        assert_send_sync::<FanotifyReader>();
+        assert_send_sync::<FanotifyResponder>();
+        assert_send_sync::<EventStream<FanotifyReader>>();
+    }
+
+    fn event(fd: RawFd, info: &[u8]) -> Vec<u8> {
+        const EVENT_SIZE: usize = size_of::<libc::fanotify_event_metadata>();
+        let metadata = libc::fanotify_event_metadata {
+            event_len: (EVENT_SIZE + info.len()) as u32,
+            vers: libc::FANOTIFY_METADATA_VERSION,
+            reserved: 0,
+            metadata_len: EVENT_SIZE as u16,
+            mask: libc::FAN_OPEN_PERM,
+            fd,
+            pid: 1,
+        };
+        let mut bytes = unsafe {
+            std::slice::from_raw_parts(
+                (&metadata as *const libc::fanotify_event_metadata).cast::<u8>(),
+                EVENT_SIZE,
+            )
+        }
+        .to_vec();
+        bytes.extend(info);
+        bytes
+    }
+
+    async fn next<S: Stream + Unpin>(stream: &mut S) -> Option<S::Item> {
+        std::future::poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)).await
+    }
+
+    #[test]
+    fn test_stream_keeps_events_around_malformed_record() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            // a socket stands in for the group
+            let (group, mut kernel) = UnixStream::pair().unwrap();
+            group.set_nonblocking(true).unwrap();
+            let mut fan = Fanotify::new(OwnedFd::from(group), InitFlags::empty())
+                .into_wrapped(AsyncFd::new)
+                .unwrap();
+
+            let fds = [(); 3].map(|_| std::fs::File::open("/dev/null").unwrap().into_raw_fd());
+            let mut bytes = event(fds[0], &[]);
+            // an info record longer than the event
+            bytes.extend(event(fds[1], &[1, 0, 100, 0]));
+            bytes.extend(event(fds[2], &[]));
+            kernel.write_all(&bytes).unwrap();
+
+            let mut stream = fan.events();
+            for fd in fds {
+                let event = next(&mut stream).await.unwrap().unwrap();
+                assert_eq!(event.fd().map(|fd| fd.as_raw_fd()), Some(fd));
+            }
+            let error = next(&mut stream).await.unwrap().err().unwrap();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        });
+    }
+}
@@ -0,0 +1,65 @@
+use std::{io::Read, os::fd::OwnedFd};
+
+use ::async_io::Async;
+
+use crate::{
+    consts::{EventFFlags, InitFlags},
+    error::FanotifyError,
+    fanotify::Fanotify,
+    messages::{Event, EventBuffer, EventIter, Response},
+};
+
+/// Fanotify for `async-io` based runtimes like smol, with the same interface as the tokio
+/// one in [`aio`](crate::aio).
+impl Fanotify<Async<Fanotify<OwnedFd>>> {
+    pub fn init(init_flags: InitFlags, event_fd_flags: EventFFlags) -> Result<Self, FanotifyError> {
+        Self::try_init(init_flags, event_fd_flags)
+    }
+
+    /// `FAN_NONBLOCK` is always added, `Async` makes the fd non blocking anyway.
+    pub fn try_init(
+        init_flags: InitFlags,
+        event_fd_flags: EventFFlags,
+    ) -> Result<Self, FanotifyError> {
+        Fanotify::<OwnedFd>::init(init_flags | InitFlags::FAN_NONBLOCK, event_fd_flags)?
+            .into_wrapped(Async::new)
+    }
+
+    pub fn init_unprivileged(
+        init_flags: InitFlags,
+        event_fd_flags: EventFFlags,
+    ) -> Result<Self, FanotifyError> {
+        Fanotify::<OwnedFd>::init_unprivileged(
+            init_flags | InitFlags::FAN_NONBLOCK,
+            event_fd_flags,
+        )?
+        .into_wrapped(Async::new)
+    }
+
+    pub fn init_fs_error_monitor<P: AsRef<std::path::Path>>(
+        path: P,
+    ) -> Result<Self, FanotifyError> {
+        Fanotify::<OwnedFd>::init_fs_error_monitor_with(path, InitFlags::FAN_NONBLOCK)?
+            .into_wrapped(Async::new)
+    }
+
+    pub async fn read_events(&mut self) -> std::io::Result<Vec<Event>> {
+        self.fd.read_with(Fanotify::read_events_once).await
+    }
+
+    pub async fn read_into<'a>(
+        &mut self,
+        buffer: &'a mut EventBuffer,
+    ) -> std::io::Result<EventIter<'a>> {
+        let nread = self
+            .fd
+            .read_with(|mut r| r.read(buffer.as_mut_slice()))
+            .await?;
+        Ok(buffer.events(nread))
+    }
+
+    /// Never waits, see [`Fanotify::write_response`].
+    pub async fn write_response(&mut self, response: Response) -> std::io::Result<usize> {
+        self.fd.get_ref().respond(&response)
+    }
+}
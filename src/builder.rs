@@ -0,0 +1,375 @@
+use std::os::fd::OwnedFd;
+
+use crate::{
+    capabilities::Capabilities,
+    consts::{EventFFlags, InitFlags},
+    error::FanotifyError,
+    fanotify::{check_unprivileged, Fanotify},
+};
+
+/// Class of a fanotify group, exactly one is passed to `fanotify_init(2)`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum NotificationClass {
+    // notification only, events are reported after the access
+    #[default]
+    Notif,
+    // permission events once the content is ready, usually used by security software
+    Content,
+    // permission events before the content is ready, usually used by storage managers
+    PreContent,
+}
+
+impl NotificationClass {
+    pub fn flags(&self) -> InitFlags {
+        match self {
+            NotificationClass::Notif => InitFlags::FAN_CLASS_NOTIF,
+            NotificationClass::Content => InitFlags::FAN_CLASS_CONTENT,
+            NotificationClass::PreContent => InitFlags::FAN_CLASS_PRE_CONTENT,
+        }
+    }
+
+    pub fn has_permission_events(&self) -> bool {
+        !matches!(self, NotificationClass::Notif)
+    }
+}
+
+/// Access mode of the fds opened for events.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum EventFdAccess {
+    #[default]
+    ReadOnly,
+    WriteOnly,
+    ReadWrite,
+}
+
+impl EventFdAccess {
+    pub fn flags(&self) -> EventFFlags {
+        match self {
+            EventFdAccess::ReadOnly => EventFFlags::O_RDONLY,
+            EventFdAccess::WriteOnly => EventFFlags::O_WRONLY,
+            EventFdAccess::ReadWrite => EventFFlags::O_RDWR,
+        }
+    }
+}
+
+/// Flag combinations `fanotify_init(2)` would reject with `EINVAL`.
+#[derive(thiserror::Error, Debug)]
+pub enum BuildError {
+    #[error("FAN_REPORT_NAME requires FAN_REPORT_DIR_FID")]
+    NameWithoutDirFid,
+
+    #[error("FAN_REPORT_TARGET_FID requires FAN_REPORT_FID and FAN_REPORT_DFID_NAME")]
+    TargetFidWithoutDfidName,
+
+    #[error("this kernel can't report file handles in {0:?} groups, only in notification groups")]
+    FidWithPermissionClass(NotificationClass),
+
+    #[error("FAN_REPORT_MNT can't be combined with file handle reporting")]
+    MntWithFid,
+
+    #[error("FAN_REPORT_PIDFD can't be combined with FAN_REPORT_TID")]
+    PidFdWithTid,
+
+    #[error("the access mode of event fds is set with FanotifyBuilder::event_fd_access")]
+    AccessModeInEventFlags,
+
+    #[error(transparent)]
+    Fanotify(#[from] FanotifyError),
+}
+
+/// Typed alternative to [`Fanotify::init`], which checks the flags before calling
+/// `fanotify_init(2)` instead of leaving it to a bare `EINVAL`.
+///
+/// Groups are created with `FAN_CLOEXEC` and event fds with `O_CLOEXEC` unless turned off.
+#[derive(Clone, Debug)]
+pub struct FanotifyBuilder {
+    class: NotificationClass,
+    // FAN_REPORT_* and the other flags besides the class
+    flags: InitFlags,
+    // flags that may be dropped by downgrade
+    optional: InitFlags,
+    access: EventFdAccess,
+    event_f_flags: EventFFlags,
+    unprivileged: bool,
+}
+
+impl FanotifyBuilder {
+    pub fn new() -> Self {
+        Self {
+            class: NotificationClass::Notif,
+            flags: InitFlags::FAN_CLOEXEC,
+            optional: InitFlags::empty(),
+            access: EventFdAccess::ReadOnly,
+            event_f_flags: EventFFlags::O_CLOEXEC,
+            unprivileged: false,
+        }
+    }
+
+    /// Preset for users without `CAP_SYS_ADMIN`: a notification group reporting file handles,
+    /// built with [`Fanotify::init_unprivileged`]. Flags that need privilege fail validation.
+    pub fn unprivileged() -> Self {
+        Self {
+            unprivileged: true,
+            ..Self::new().report_fid()
+        }
+    }
+
+    pub fn class(mut self, class: NotificationClass) -> Self {
+        self.class = class;
+        self
+    }
+
+    // reporting modes
+    pub fn report_tid(self) -> Self {
+        self.flag(InitFlags::FAN_REPORT_TID, true)
+    }
+    pub fn report_fid(self) -> Self {
+        self.flag(InitFlags::FAN_REPORT_FID, true)
+    }
+    pub fn report_dir_fid(self) -> Self {
+        self.flag(InitFlags::FAN_REPORT_DIR_FID, true)
+    }
+    pub fn report_name(self) -> Self {
+        self.flag(InitFlags::FAN_REPORT_NAME, true)
+    }
+    pub fn report_dfid_name(self) -> Self {
+        self.flag(InitFlags::FAN_REPORT_DFID_NAME, true)
+    }
+    pub fn report_target_fid(self) -> Self {
+        self.flag(InitFlags::FAN_REPORT_TARGET_FID, true)
+    }
+    pub fn report_pidfd(self) -> Self {
+        self.flag(InitFlags::FAN_REPORT_PIDFD, true)
+    }
+    pub fn report_mnt(self) -> Self {
+        self.flag(InitFlags::FAN_REPORT_MNT, true)
+    }
+
+    // group flags
+    pub fn cloexec(self, enable: bool) -> Self {
+        self.flag(InitFlags::FAN_CLOEXEC, enable)
+    }
+    pub fn nonblocking(self, enable: bool) -> Self {
+        self.flag(InitFlags::FAN_NONBLOCK, enable)
+    }
+    pub fn unlimited_queue(self) -> Self {
+        self.flag(InitFlags::FAN_UNLIMITED_QUEUE, true)
+    }
+    pub fn unlimited_marks(self) -> Self {
+        self.flag(InitFlags::FAN_UNLIMITED_MARKS, true)
+    }
+    pub fn enable_audit(self) -> Self {
+        self.flag(InitFlags::FAN_ENABLE_AUDIT, true)
+    }
+
+    // event fd flags
+    pub fn event_fd_access(mut self, access: EventFdAccess) -> Self {
+        self.access = access;
+        self
+    }
+    /// Adds `open(2)` flags like `O_LARGEFILE` or `O_NOATIME` to event fds.
+    /// The access mode is set with [`FanotifyBuilder::event_fd_access`].
+    pub fn event_fd_flags(mut self, flags: EventFFlags) -> Self {
+        self.event_f_flags |= flags;
+        self
+    }
+    pub fn event_fd_cloexec(mut self, enable: bool) -> Self {
+        self.event_f_flags.set(EventFFlags::O_CLOEXEC, enable);
+        self
+    }
+
+    /// Marks `flags` as nice to have, [`FanotifyBuilder::downgrade`] drops them if they are
+    /// not supported. They still have to be enabled with the methods above.
+    pub fn optional(mut self, flags: InitFlags) -> Self {
+        self.optional |= flags;
+        self
+    }
+
+    /// Drops the optional flags `capabilities` lacks, together with optional flags that
+    /// depend on them, e.g. `FAN_REPORT_NAME` on `FAN_REPORT_DIR_FID`.
+    /// Missing flags that are not optional are left for [`FanotifyBuilder::build`] to fail on.
+    pub fn downgrade(mut self, capabilities: &Capabilities) -> Self {
+        let mut unsupported = InitFlags::empty();
+        for (_, flag) in (self.flags & self.optional).iter_names() {
+            if !capabilities.supports_init(flag) {
+                unsupported |= flag;
+            }
+        }
+        let mut flags = self.flags - unsupported;
+
+        let mut dependants = InitFlags::empty();
+        if !flags.contains(InitFlags::FAN_REPORT_DIR_FID) {
+            dependants |= InitFlags::FAN_REPORT_NAME | InitFlags::FAN_REPORT_TARGET_FID;
+        }
+        if !flags.contains(InitFlags::FAN_REPORT_FID) {
+            dependants |= InitFlags::FAN_REPORT_TARGET_FID;
+        }
+        flags -= dependants & self.optional;
+
+        self.flags = flags;
+        self
+    }
+
+    fn flag(mut self, flag: InitFlags, enable: bool) -> Self {
+        self.flags.set(flag, enable);
+        self
+    }
+
+    pub fn init_flags(&self) -> InitFlags {
+        self.class.flags() | self.flags
+    }
+    pub fn event_f_flags(&self) -> EventFFlags {
+        self.access.flags() | self.event_f_flags
+    }
+
+    /// Checks the flag combination, without calling into the kernel.
+    ///
+    /// Flags the running kernel doesn't know about still fail with `EINVAL` on build, apart
+    /// from file handles in permission class groups, which build checks with
+    /// [`Capabilities::probe`].
+    pub fn validate(&self) -> Result<(), BuildError> {
+        let flags = self.flags;
+        let fid = self.reports_fid();
+
+        if flags.contains(InitFlags::FAN_REPORT_NAME)
+            && !flags.contains(InitFlags::FAN_REPORT_DIR_FID)
+        {
+            return Err(BuildError::NameWithoutDirFid);
+        }
+        if flags.contains(InitFlags::FAN_REPORT_TARGET_FID)
+            && !flags.contains(InitFlags::FAN_REPORT_FID | InitFlags::FAN_REPORT_DFID_NAME)
+        {
+            return Err(BuildError::TargetFidWithoutDfidName);
+        }
+        if fid && flags.contains(InitFlags::FAN_REPORT_MNT) {
+            return Err(BuildError::MntWithFid);
+        }
+        if flags.contains(InitFlags::FAN_REPORT_PIDFD | InitFlags::FAN_REPORT_TID) {
+            return Err(BuildError::PidFdWithTid);
+        }
+        if self
+            .event_f_flags
+            .intersects(EventFFlags::O_WRONLY | EventFFlags::O_RDWR)
+        {
+            return Err(BuildError::AccessModeInEventFlags);
+        }
+        if self.unprivileged {
+            check_unprivileged(self.init_flags(), self.event_f_flags())?;
+        }
+        Ok(())
+    }
+
+    fn reports_fid(&self) -> bool {
+        self.flags.intersects(
+            InitFlags::FAN_REPORT_FID
+                | InitFlags::FAN_REPORT_DIR_FID
+                | InitFlags::FAN_REPORT_TARGET_FID,
+        )
+    }
+
+    pub fn build(&self) -> Result<Fanotify<OwnedFd>, BuildError> {
+        self.build_with(InitFlags::empty())
+    }
+
+    // the async builds wrap a plain group made here
+    fn build_with(&self, init_flags: InitFlags) -> Result<Fanotify<OwnedFd>, BuildError> {
+        self.validate()?;
+        // no kernel accepts this so far, permission events are always reported with an fd.
+        // Without CAP_SYS_ADMIN the probe can't tell, and building fails anyway
+        if self.class.has_permission_events() && self.reports_fid() {
+            let capabilities = Capabilities::probe();
+            if capabilities.cap_sys_admin && !capabilities.permission_events_with_fid {
+                return Err(BuildError::FidWithPermissionClass(self.class));
+            }
+        }
+        let (init_flags, event_f_flags) = (self.init_flags() | init_flags, self.event_f_flags());
+        if self.unprivileged {
+            return Ok(Fanotify::<OwnedFd>::init_unprivileged(
+                init_flags,
+                event_f_flags,
+            )?);
+        }
+        Ok(Fanotify::<OwnedFd>::init(init_flags, event_f_flags)?)
+    }
+
+    /// Builds a group for tokio, `FAN_NONBLOCK` is always added.
+    #[cfg(feature = "aio")]
+    pub fn build_async(
+        &self,
+    ) -> Result<Fanotify<tokio::io::unix::AsyncFd<Fanotify<OwnedFd>>>, BuildError> {
+        let fan = self.build_with(InitFlags::FAN_NONBLOCK)?;
+        Ok(fan.into_wrapped(tokio::io::unix::AsyncFd::new)?)
+    }
+
+    /// Builds a group for async-io based runtimes, `FAN_NONBLOCK` is always added.
+    #[cfg(feature = "async-io")]
+    pub fn build_async_io(
+        &self,
+    ) -> Result<Fanotify<::async_io::Async<Fanotify<OwnedFd>>>, BuildError> {
+        let fan = self.build_with(InitFlags::FAN_NONBLOCK)?;
+        Ok(fan.into_wrapped(::async_io::Async::new)?)
+    }
+}
+
+impl Default for FanotifyBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BuildError, FanotifyBuilder, NotificationClass};
+    use crate::{
+        consts::{EventFFlags, InitFlags},
+        error::FanotifyError,
+        fanotify::check_unprivileged,
+    };
+
+    #[test]
+    fn test_validate() {
+        assert!(FanotifyBuilder::new().report_dfid_name().validate().is_ok());
+        assert!(matches!(
+            FanotifyBuilder::new().report_name().validate(),
+            Err(BuildError::NameWithoutDirFid)
+        ));
+        assert!(matches!(
+            FanotifyBuilder::new()
+                .report_fid()
+                .report_target_fid()
+                .validate(),
+            Err(BuildError::TargetFidWithoutDfidName)
+        ));
+        // left to build, which asks the kernel
+        assert!(FanotifyBuilder::new()
+            .class(NotificationClass::Content)
+            .report_fid()
+            .validate()
+            .is_ok());
+        assert!(matches!(
+            FanotifyBuilder::unprivileged().unlimited_queue().validate(),
+            Err(BuildError::Fanotify(FanotifyError::RequiresPrivilege {
+                what: "FAN_UNLIMITED_QUEUE",
+                ..
+            }))
+        ));
+        assert!(matches!(
+            check_unprivileged(InitFlags::FAN_REPORT_MNT, EventFFlags::O_RDONLY),
+            Err(FanotifyError::RequiresPrivilege {
+                what: "FAN_REPORT_MNT",
+                ..
+            })
+        ));
+        assert!(FanotifyBuilder::unprivileged()
+            .report_dfid_name()
+            .validate()
+            .is_ok());
+        assert!(matches!(
+            FanotifyBuilder::new()
+                .report_pidfd()
+                .report_tid()
+                .validate(),
+            Err(BuildError::PidFdWithTid)
+        ));
+    }
+}
@@ -0,0 +1,433 @@
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+};
+
+use crate::{
+    capabilities::probe_mark_flag,
+    consts::{MarkFlags, MaskFlags},
+    error::{Errno, ErrorContext, FanotifyError},
+    fanotify::Fanotify,
+    permission::PermissionRequest,
+};
+
+/// Remembers allowed files by adding ignore marks for them, so the kernel stops asking.
+///
+/// [`VerdictCache::insert`] ignores the permission event's mask on the file's inode with
+/// `FAN_MARK_IGNORE` (`FAN_MARK_IGNORED_MASK` before Linux 6.0). Without
+/// `FAN_MARK_IGNORED_SURV_MODIFY` the kernel drops the ignore mask as soon as the file is
+/// modified, and asks again. Only regular files are cached.
+///
+/// The kernel doesn't tell about accesses it let through, so a hit is counted when a cached file
+/// is asked for again unchanged, after its mark was evicted, see [`VerdictCache::evictable`].
+///
+/// NOTE: the cache is keyed by inode, renaming or linking a file doesn't invalidate it, so
+/// verdicts depending on the path must not be cached.
+/// Call [`VerdictCache::invalidate`] when the verdict could change for another reason.
+pub struct VerdictCache {
+    inner: Mutex<Inner>,
+    capacity: usize,
+    // FAN_MARK_IGNORE, or FAN_MARK_IGNORED_MASK before Linux 6.0, probed on the first mark
+    ignore: OnceLock<MarkFlags>,
+    evictable: bool,
+    stats: CacheStats,
+}
+
+struct Inner {
+    entries: HashMap<(u64, u64), Entry>,
+    tick: u64,
+}
+
+struct Entry {
+    mask: MaskFlags,
+    // ctime in seconds and nanoseconds, any change to the inode changes it
+    ctime: (i64, i64),
+    // to find the inode again when it is evicted from the cache
+    path: PathBuf,
+    // least recently used entry is evicted first
+    tick: u64,
+}
+
+/// Counters of a [`VerdictCache`].
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    inserts: AtomicU64,
+    evictions: AtomicU64,
+    invalidations: AtomicU64,
+}
+
+impl CacheStats {
+    /// Requests for cached files that weren't changed since.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+    /// Requests for files not in the cache, or changed since they were cached.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+    pub fn inserts(&self) -> u64 {
+        self.inserts.load(Ordering::Relaxed)
+    }
+    /// Entries dropped to stay within the capacity.
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+    pub fn invalidations(&self) -> u64 {
+        self.invalidations.load(Ordering::Relaxed)
+    }
+}
+
+impl VerdictCache {
+    pub const DEFAULT_CAPACITY: usize = 4096;
+
+    pub fn new() -> Self {
+        Self::with_capacity(Self::DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                tick: 0,
+            }),
+            capacity,
+            ignore: OnceLock::new(),
+            evictable: false,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Adds the ignore marks with `FAN_MARK_EVICTABLE` (Linux 5.19), so they don't keep the
+    /// inodes in memory. Files whose inode is marked by the group otherwise aren't cached.
+    pub fn evictable(mut self) -> Self {
+        self.evictable = true;
+        self
+    }
+
+    pub fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether `request` is for a cached file that wasn't changed since. The ignore mark is
+    /// added again then, as the kernel must have dropped it to ask.
+    pub fn lookup<F: AsRawFd>(&self, fan: &Fanotify<F>, request: &PermissionRequest) -> bool {
+        let Ok(stat) = stat(request.fd()) else {
+            self.stats.misses.fetch_add(1, Ordering::Relaxed);
+            return false;
+        };
+        let key = (stat.st_dev, stat.st_ino);
+        let mask = request.event().mask();
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let Some(entry) = inner.entries.get_mut(&key) else {
+            self.stats.misses.fetch_add(1, Ordering::Relaxed);
+            return false;
+        };
+        if entry.ctime != (stat.st_ctime, stat.st_ctime_nsec) {
+            inner.entries.remove(&key);
+            self.stats.misses.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        if !entry.mask.contains(mask) {
+            self.stats.misses.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        entry.tick = tick;
+        let _ = self.add_mark(fan, entry.mask, request.fd());
+        self.stats.hits.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Caches an allowed request, by ignoring its event's mask on the file's inode.
+    /// Returns whether the file was cached, only regular files are.
+    pub fn insert<F: AsRawFd>(
+        &self,
+        fan: &Fanotify<F>,
+        request: &PermissionRequest,
+    ) -> Result<bool, FanotifyError> {
+        if self.capacity == 0 {
+            return Ok(false);
+        }
+        let fd = request.fd();
+        let stat = stat(fd).map_err(|error| io_error(error, None))?;
+        if stat.st_mode & libc::S_IFMT != libc::S_IFREG {
+            return Ok(false);
+        }
+        let key = (stat.st_dev, stat.st_ino);
+        let path =
+            std::fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd())).unwrap_or_default();
+
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.entries.contains_key(&key) && inner.entries.len() >= self.capacity {
+            let oldest = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.tick)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                let entry = inner.entries.remove(&oldest).unwrap();
+                let _ = self.remove_mark(fan, oldest, &entry.path, entry.mask);
+                self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let mask = inner
+            .entries
+            .get(&key)
+            .filter(|entry| entry.ctime == (stat.st_ctime, stat.st_ctime_nsec))
+            .map_or(MaskFlags::empty(), |entry| entry.mask)
+            | request.event().mask();
+        self.add_mark(fan, mask, fd)?;
+
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.entries.insert(
+            key,
+            Entry {
+                mask,
+                ctime: (stat.st_ctime, stat.st_ctime_nsec),
+                path,
+                tick,
+            },
+        );
+        self.stats.inserts.fetch_add(1, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    /// Forgets the file at `path` and removes its ignore mark, so the next access is asked for
+    /// again. Returns whether it was cached.
+    pub fn invalidate<F: AsRawFd, P: AsRef<Path>>(
+        &self,
+        fan: &Fanotify<F>,
+        path: P,
+    ) -> Result<bool, FanotifyError> {
+        let path = path.as_ref();
+        let file = open_path(path).map_err(|error| io_error(error, Some(path)))?;
+        let stat = stat(file.as_fd()).map_err(|error| io_error(error, Some(path)))?;
+        let key = (stat.st_dev, stat.st_ino);
+
+        let mut inner = self.inner.lock().unwrap();
+        let Some(entry) = inner.entries.remove(&key) else {
+            return Ok(false);
+        };
+        self.stats.invalidations.fetch_add(1, Ordering::Relaxed);
+        self.remove_mark(fan, key, path, entry.mask)?;
+        Ok(true)
+    }
+
+    /// Forgets every file, removing the ignore marks of those that can still be found.
+    pub fn clear<F: AsRawFd>(&self, fan: &Fanotify<F>) {
+        let mut inner = self.inner.lock().unwrap();
+        for (key, entry) in inner.entries.drain() {
+            let _ = self.remove_mark(fan, key, &entry.path, entry.mask);
+            self.stats.invalidations.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn ignore(&self) -> MarkFlags {
+        *self.ignore.get_or_init(|| {
+            if probe_mark_flag(MarkFlags::FAN_MARK_IGNORE) {
+                MarkFlags::FAN_MARK_IGNORE
+            } else {
+                MarkFlags::FAN_MARK_IGNORED_MASK
+            }
+        })
+    }
+
+    fn add_mark<F: AsRawFd>(
+        &self,
+        fan: &Fanotify<F>,
+        mask: MaskFlags,
+        fd: BorrowedFd,
+    ) -> Result<(), FanotifyError> {
+        let mut flags = MarkFlags::FAN_MARK_ADD | self.ignore();
+        if self.evictable {
+            flags |= MarkFlags::FAN_MARK_EVICTABLE;
+        }
+        fan.mark_unrecorded(flags, mask, Some(fd), None::<&Path>)
+    }
+
+    // the path is only trusted if it still leads to the cached inode, e.g. not after a rename
+    fn remove_mark<F: AsRawFd>(
+        &self,
+        fan: &Fanotify<F>,
+        key: (u64, u64),
+        path: &Path,
+        mask: MaskFlags,
+    ) -> Result<(), FanotifyError> {
+        let Ok(file) = open_path(path) else {
+            return Ok(());
+        };
+        if stat(file.as_fd())
+            .map(|stat| (stat.st_dev, stat.st_ino))
+            .ok()
+            != Some(key)
+        {
+            return Ok(());
+        }
+        // fanotify_mark(2) refuses O_PATH fds, but resolves their /proc link to the same inode.
+        // The mark is gone already if the kernel evicted it
+        let link = PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()));
+        let operation = MarkFlags::FAN_MARK_REMOVE | self.ignore();
+        match fan.mark_unrecorded(operation, mask, None, Some(&link)) {
+            Err(error) if error.errno() == Some(Errno::new(libc::ENOENT)) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+impl Default for VerdictCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn io_error(error: std::io::Error, path: Option<&Path>) -> FanotifyError {
+    FanotifyError::Io {
+        source: error,
+        context: ErrorContext {
+            path: path.map(Path::to_owned),
+            ..ErrorContext::default()
+        },
+    }
+}
+
+// O_PATH doesn't generate fanotify events, so this can't wait for our own permission events
+fn open_path(path: &Path) -> std::io::Result<OwnedFd> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn stat(fd: BorrowedFd) -> std::io::Result<libc::stat> {
+    let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+    unsafe {
+        if libc::fstat(fd.as_raw_fd(), stat.as_mut_ptr()) == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(stat.assume_init())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        os::fd::{IntoRawFd, OwnedFd},
+        path::{Path, PathBuf},
+    };
+
+    use super::VerdictCache;
+    use crate::{
+        consts::{EventFFlags, InitFlags},
+        fanotify::Fanotify,
+        messages::{Event, Verdict},
+        permission::PermissionRequest,
+    };
+
+    // ignore marks for permission events need a permission class group
+    fn group() -> Fanotify<OwnedFd> {
+        Fanotify::<OwnedFd>::init(
+            InitFlags::FAN_CLASS_CONTENT | InitFlags::FAN_CLOEXEC,
+            EventFFlags::O_RDONLY,
+        )
+        .unwrap()
+    }
+
+    fn file(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("fanotify-cache-{}-{name}", std::process::id()));
+        std::fs::write(&path, name).unwrap();
+        path
+    }
+
+    // a request as if `path` was opened, answering it writes to no pending event and fails
+    fn request(fan: &Fanotify<OwnedFd>, path: &Path) -> PermissionRequest {
+        let metadata = libc::fanotify_event_metadata {
+            event_len: size_of::<libc::fanotify_event_metadata>() as u32,
+            vers: libc::FANOTIFY_METADATA_VERSION,
+            reserved: 0,
+            metadata_len: size_of::<libc::fanotify_event_metadata>() as u16,
+            mask: libc::FAN_OPEN_PERM,
+            fd: std::fs::File::open(path).unwrap().into_raw_fd(),
+            pid: 1,
+        };
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                (&metadata as *const libc::fanotify_event_metadata).cast::<u8>(),
+                size_of::<libc::fanotify_event_metadata>(),
+            )
+        };
+        let event = Event::extract_from(bytes).unwrap().pop().unwrap();
+        event.into_permission_request(fan, Verdict::Allow).unwrap()
+    }
+
+    #[test]
+    #[ignore = "needs CAP_SYS_ADMIN"]
+    fn test_changed_file_misses() {
+        let fan = group();
+        let path = file("changed");
+        let cache = VerdictCache::new();
+        assert!(cache.insert(&fan, &request(&fan, &path)).unwrap());
+        assert!(cache.lookup(&fan, &request(&fan, &path)));
+
+        // changes the ctime
+        let mut permissions = std::fs::metadata(&path).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&path, permissions).unwrap();
+        assert!(!cache.lookup(&fan, &request(&fan, &path)));
+        assert!(cache.is_empty());
+        assert_eq!((cache.stats().hits(), cache.stats().misses()), (1, 1));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs CAP_SYS_ADMIN"]
+    fn test_capacity() {
+        let fan = group();
+        let (first, second) = (file("first"), file("second"));
+        let cache = VerdictCache::with_capacity(1);
+        assert!(cache.insert(&fan, &request(&fan, &first)).unwrap());
+        assert!(cache.insert(&fan, &request(&fan, &second)).unwrap());
+        assert_eq!((cache.len(), cache.stats().evictions()), (1, 1));
+        assert!(!cache.lookup(&fan, &request(&fan, &first)));
+        assert!(cache.lookup(&fan, &request(&fan, &second)));
+        std::fs::remove_file(first).unwrap();
+        std::fs::remove_file(second).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs CAP_SYS_ADMIN"]
+    fn test_no_capacity() {
+        let fan = group();
+        let path = file("uncached");
+        let cache = VerdictCache::with_capacity(0);
+        assert!(!cache.insert(&fan, &request(&fan, &path)).unwrap());
+        assert!(cache.is_empty());
+        assert_eq!((cache.stats().inserts(), cache.stats().evictions()), (0, 0));
+        std::fs::remove_file(path).unwrap();
+    }
+}
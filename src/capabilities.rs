@@ -0,0 +1,203 @@
+use std::{os::fd::OwnedFd, path::Path, sync::OnceLock};
+
+use bitflags::Flags;
+
+use crate::{
+    consts::{EventFFlags, InitFlags, MarkFlags, MaskFlags},
+    error::FanotifyError,
+    fanotify::Fanotify,
+};
+
+/// What the running kernel supports and this process may use, found out with throwaway
+/// `fanotify_init(2)` and `fanotify_mark(2)` calls.
+///
+/// A flag counts as supported when the kernel accepts it from this process, so flags
+/// that need `CAP_SYS_ADMIN`, like the permission classes, are missing without it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    pub init_flags: InitFlags,
+    pub mark_flags: MarkFlags,
+    pub mask_flags: MaskFlags,
+    // FAN_MARK_MNTNS has the bits of FAN_MARK_MOUNT|FAN_MARK_FILESYSTEM, so it can't be told
+    // apart in mark_flags
+    pub mount_namespace_marks: bool,
+    pub cap_sys_admin: bool,
+    // no CAP_SYS_ADMIN, but unprivileged groups can be created, Linux 5.13
+    pub unprivileged: bool,
+    // permission class groups reporting file handles, refused by every kernel so far.
+    // Unknown without CAP_SYS_ADMIN, then false
+    pub permission_events_with_fid: bool,
+}
+
+static CAPABILITIES: OnceLock<Capabilities> = OnceLock::new();
+
+impl Capabilities {
+    /// Probes once per process, later calls return the cached result.
+    pub fn probe() -> &'static Capabilities {
+        CAPABILITIES.get_or_init(Self::probe_uncached)
+    }
+
+    pub fn probe_uncached() -> Self {
+        let cap_sys_admin = has_cap_sys_admin();
+
+        // every named flag on its own, iter_names would skip the ones sharing bits with others
+        let mut init_flags = InitFlags::empty();
+        for flag in InitFlags::FLAGS.iter().map(|flag| *flag.value()) {
+            if init(flag | init_requirements(flag)).is_some() {
+                init_flags |= flag;
+            }
+        }
+        let unprivileged = !cap_sys_admin && init_flags.contains(InitFlags::FAN_REPORT_FID);
+        let permission_events_with_fid = cap_sys_admin
+            && init(InitFlags::FAN_CLASS_CONTENT | InitFlags::FAN_REPORT_FID).is_some();
+
+        let mount_namespace_marks = probe_mark_flag(MarkFlags::FAN_MARK_MNTNS);
+        let mut mark_flags = MarkFlags::empty();
+        for flag in MarkFlags::FLAGS.iter().map(|flag| *flag.value()) {
+            if flag != MarkFlags::FAN_MARK_MNTNS && probe_mark_flag(flag) {
+                mark_flags |= flag;
+            }
+        }
+
+        let mut mask_flags = MaskFlags::empty();
+        for flag in MaskFlags::FLAGS.iter().map(|flag| *flag.value()) {
+            if probe_mask_flag(flag) {
+                mask_flags |= flag;
+            }
+        }
+
+        Self {
+            init_flags,
+            mark_flags,
+            mask_flags,
+            mount_namespace_marks,
+            cap_sys_admin,
+            unprivileged,
+            permission_events_with_fid,
+        }
+    }
+
+    pub fn supports_init(&self, flags: InitFlags) -> bool {
+        self.init_flags.contains(flags)
+    }
+    pub fn supports_mark(&self, flags: MarkFlags) -> bool {
+        if flags.contains(MarkFlags::FAN_MARK_MNTNS) && !self.mount_namespace_marks {
+            return false;
+        }
+        self.mark_flags.contains(flags)
+    }
+    pub fn supports_mask(&self, mask: MaskFlags) -> bool {
+        self.mask_flags.contains(mask)
+    }
+}
+
+// flags the kernel refuses to see alone
+fn init_requirements(flag: InitFlags) -> InitFlags {
+    if flag.contains(InitFlags::FAN_REPORT_TARGET_FID) {
+        InitFlags::FAN_REPORT_DFID_NAME | InitFlags::FAN_REPORT_FID
+    } else if flag.contains(InitFlags::FAN_REPORT_NAME) {
+        InitFlags::FAN_REPORT_DIR_FID
+    } else {
+        InitFlags::empty()
+    }
+}
+
+fn init(flags: InitFlags) -> Option<Fanotify<OwnedFd>> {
+    Fanotify::<OwnedFd>::try_init(flags | InitFlags::FAN_CLOEXEC, EventFFlags::O_RDONLY).ok()
+}
+
+// group reporting file handles if possible, which is the only kind unprivileged users get
+fn notification_group() -> Option<Fanotify<OwnedFd>> {
+    init(InitFlags::FAN_REPORT_DFID_NAME | InitFlags::FAN_REPORT_FID)
+        .or_else(|| init(InitFlags::FAN_REPORT_FID))
+        .or_else(|| init(InitFlags::FAN_CLASS_NOTIF))
+}
+
+// EINVAL is the answer to unknown flags, other errors come from checks after the flags
+// were accepted, e.g. EISDIR for an ignore mask on a directory without FAN_ONDIR
+fn accepted(fan: &Fanotify<OwnedFd>, operation: MarkFlags, mask: MaskFlags, path: &str) -> bool {
+    match fan.mark(operation, mask, None, Some(Path::new(path))) {
+        Ok(()) => true,
+        Err(error) => !matches!(
+            error,
+            FanotifyError::Unsupported { .. } | FanotifyError::MissingCapability { .. }
+        ),
+    }
+}
+
+pub(crate) fn probe_mark_flag(flag: MarkFlags) -> bool {
+    if flag.contains(MarkFlags::FAN_MARK_MNTNS) {
+        return init(InitFlags::FAN_REPORT_MNT).is_some_and(|fan| {
+            accepted(
+                &fan,
+                MarkFlags::FAN_MARK_ADD | flag,
+                MaskFlags::FAN_MNT_ATTACH,
+                "/proc/self/ns/mnt",
+            )
+        });
+    }
+    let Some(fan) = notification_group() else {
+        return false;
+    };
+    let operations =
+        MarkFlags::FAN_MARK_ADD | MarkFlags::FAN_MARK_REMOVE | MarkFlags::FAN_MARK_FLUSH;
+    let operation = if operations.intersects(flag) {
+        flag
+    } else {
+        MarkFlags::FAN_MARK_ADD | flag
+    };
+    accepted(&fan, operation, MaskFlags::FAN_OPEN, "/")
+}
+
+fn probe_mask_flag(flag: MaskFlags) -> bool {
+    let (fan, operation, path) = if flag.intersects(
+        MaskFlags::FAN_OPEN_PERM
+            | MaskFlags::FAN_ACCESS_PERM
+            | MaskFlags::FAN_OPEN_EXEC_PERM
+            | MaskFlags::FAN_PRE_ACCESS,
+    ) {
+        (
+            init(InitFlags::FAN_CLASS_PRE_CONTENT),
+            MarkFlags::FAN_MARK_ADD,
+            "/",
+        )
+    } else if flag.intersects(MaskFlags::FAN_MNT_ATTACH | MaskFlags::FAN_MNT_DETACH) {
+        (
+            init(InitFlags::FAN_REPORT_MNT),
+            MarkFlags::FAN_MARK_ADD | MarkFlags::FAN_MARK_MNTNS,
+            "/proc/self/ns/mnt",
+        )
+    } else if flag.contains(MaskFlags::FAN_FS_ERROR) {
+        (
+            init(InitFlags::FS_ERROR_MONITOR),
+            MarkFlags::FAN_MARK_ADD | MarkFlags::FAN_MARK_FILESYSTEM,
+            "/",
+        )
+    } else {
+        (notification_group(), MarkFlags::FAN_MARK_ADD, "/")
+    };
+    let Some(fan) = fan else {
+        return false;
+    };
+
+    // the event flags are only meaningful next to an event
+    let mask = if flag.intersects(MaskFlags::FAN_ONDIR | MaskFlags::FAN_EVENT_ON_CHILD) {
+        flag | MaskFlags::FAN_OPEN
+    } else {
+        flag
+    };
+    accepted(&fan, operation, mask, path)
+}
+
+// CAP_SYS_ADMIN in the effective set, as listed in /proc/self/status
+fn has_cap_sys_admin() -> bool {
+    const CAP_SYS_ADMIN: u32 = 21;
+    let Ok(status) = std::fs::read_to_string("/proc/self/status") else {
+        return false;
+    };
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .is_some_and(|caps| caps & (1 << CAP_SYS_ADMIN) != 0)
+}
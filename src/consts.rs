@@ -16,6 +16,27 @@ pub use libc::{
     FANOTIFY_METADATA_VERSION,
 };
 
+// not in libc yet: pre-content events, Linux 6.14
+pub const FAN_PRE_ACCESS: u64 = 0x0010_0000;
+pub const FAN_EVENT_INFO_TYPE_RANGE: u8 = 6;
+
+// not in libc yet: mount namespace marks, Linux 6.15
+pub const FAN_MARK_MNTNS: u32 = 0x0000_0110;
+pub const FAN_MNT_ATTACH: u64 = 0x0100_0000;
+pub const FAN_MNT_DETACH: u64 = 0x0200_0000;
+pub const FAN_REPORT_MNT: u32 = 0x0000_4000;
+pub const FAN_EVENT_INFO_TYPE_MNT: u8 = 7;
+
+// not in libc yet: errno other than EPERM in the upper byte of a FAN_DENY response, Linux 6.14
+pub const FAN_ERRNO_BITS: u32 = 8;
+pub const FAN_ERRNO_SHIFT: u32 = 32 - FAN_ERRNO_BITS;
+pub const FAN_ERRNO_MASK: u32 = (1 << FAN_ERRNO_BITS) - 1;
+
+// #define FAN_DENY_ERRNO(err)
+pub const fn fan_deny_errno(errno: i32) -> u32 {
+    FAN_DENY | ((errno as u32 & FAN_ERRNO_MASK) << FAN_ERRNO_SHIFT)
+}
+
 pub use libc::{
     O_APPEND, O_CLOEXEC, O_DSYNC, O_LARGEFILE, O_NOATIME, O_NONBLOCK, O_RDONLY, O_RDWR, O_SYNC,
     O_WRONLY,
@@ -23,6 +44,7 @@ pub use libc::{
 
 // NOTE: the definitions is handwritten in 2025-04-03, on Debian trixie(testing) 6.12.20-1 x86_64
 // it may need updating for future kernel updates, and pls update this comment for maintainence
+// flags of Linux 6.14 and 6.15 were added from the uapi headers, they are declared above until libc has them
 fa_bitflags! {
     pub struct InitFlags: u32 {
         /*
@@ -50,6 +72,7 @@ fa_bitflags! {
         FAN_REPORT_TARGET_FID; // Linux 5.17 / 5.15.154 / 5.10.220
         FAN_REPORT_DFID_NAME_TARGET; // Linux 5.17 / 5.15.154 / 5.10.220, FAN_REPORT_DFID_NAME|FAN_REPORT_FID|FAN_REPORT_TARGET_FID
         FAN_REPORT_PIDFD; // Linux 5.15 / 5.10.220
+        FAN_REPORT_MNT; // Linux 6.15, required by mount events, can't be combined with FAN_REPORT_FID
     }
 
     pub struct EventFFlags: ~u32 {
@@ -78,6 +101,7 @@ fa_bitflags! {
         FAN_MARK_ONLYDIR;
         FAN_MARK_MOUNT;
         FAN_MARK_FILESYSTEM; // Linux 4.20
+        FAN_MARK_MNTNS; // Linux 6.15, FAN_MARK_MOUNT|FAN_MARK_FILESYSTEM bits, marks a mount namespace fd
         FAN_MARK_IGNORED_MASK;
         FAN_MARK_IGNORE; // Linux 6.0 / 5.15.154 / 5.10.220
         FAN_MARK_IGNORED_SURV_MODIFY;
@@ -103,11 +127,14 @@ fa_bitflags! {
         FAN_MOVE; // Linux 5.1, FAN_MOVED_FROM|FAN_MOVED_TO
         FAN_RENAME; // Linux 5.17 / 5.15.154 / 5.10.220
         FAN_MOVE_SELF; // Linux 5.1
+        FAN_MNT_ATTACH; // Linux 6.15, only on FAN_MARK_MNTNS marks
+        FAN_MNT_DETACH; // Linux 6.15, only on FAN_MARK_MNTNS marks
 
         // Permissions, need FAN_CLASS_CONTENT or FAN_CLASS_PRE_CONTENT on init
         FAN_OPEN_PERM;
         FAN_ACCESS_PERM;
         FAN_OPEN_EXEC_PERM; // Linux 5.0
+        FAN_PRE_ACCESS; // Linux 6.14, needs FAN_CLASS_PRE_CONTENT
 
         // Flags
         FAN_ONDIR; // enable events on directories
@@ -115,11 +142,19 @@ fa_bitflags! {
     }
 }
 
+impl InitFlags {
+    // FAN_FS_ERROR is only reported to notification groups identifying objects by file handle
+    pub const FS_ERROR_MONITOR: Self = Self::FAN_CLASS_NOTIF.union(Self::FAN_REPORT_FID);
+}
+
 impl MaskFlags {
+    // events on directories carry FAN_ONDIR next to the event bit
     pub fn is_permission_event(&self) -> bool {
-        match self.bits() {
-            FAN_OPEN_PERM | FAN_ACCESS_PERM | FAN_OPEN_EXEC_PERM => true,
-            _ => false,
-        }
+        self.intersects(
+            Self::FAN_OPEN_PERM
+                | Self::FAN_ACCESS_PERM
+                | Self::FAN_OPEN_EXEC_PERM
+                | Self::FAN_PRE_ACCESS,
+        )
     }
 }
\ No newline at end of file
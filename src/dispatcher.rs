@@ -0,0 +1,540 @@
+use std::{
+    collections::VecDeque,
+    io::{Read, Write},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd},
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cache::VerdictCache,
+    fanotify::Fanotify,
+    messages::{Event, EventBuffer, EventRef, Response, Verdict},
+    permission::{PermissionHandler, PermissionRequest},
+    shutdown::{wait_readable, ShutdownHandle},
+};
+
+/// Answers permission events on a pool of worker threads, so one slow decision doesn't hold
+/// up every other process accessing marked files.
+///
+/// [`PermissionDispatcher::run`] reads on the calling thread and queues permission events for
+/// the workers. When the queue is full the reader stops reading, and the kernel keeps the
+/// events, so a slow handler slows down accessing processes instead of piling up memory.
+/// Requests not answered before the deadline get the default verdict from a watchdog thread,
+/// and a handler answering late is ignored. Other events are dropped.
+pub struct PermissionDispatcher {
+    fan: Arc<Fanotify<OwnedFd>>,
+    shutdown: ShutdownHandle,
+    default: Verdict,
+    workers: usize,
+    queue_size: usize,
+    deadline: Option<Duration>,
+    cache: Option<VerdictCache>,
+    stats: Arc<DispatcherStats>,
+}
+
+/// Counters of a [`PermissionDispatcher`], updated while it runs.
+#[derive(Debug, Default)]
+pub struct DispatcherStats {
+    received: AtomicU64,
+    answered: AtomicU64,
+    timed_out: AtomicU64,
+    panicked: AtomicU64,
+    failed: AtomicU64,
+    ignored: AtomicU64,
+}
+
+impl DispatcherStats {
+    /// Permission events queued for the workers.
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+    /// Requests answered with the handler's verdict.
+    pub fn answered(&self) -> u64 {
+        self.answered.load(Ordering::Relaxed)
+    }
+    /// Requests answered with the default verdict because the deadline passed.
+    pub fn timed_out(&self) -> u64 {
+        self.timed_out.load(Ordering::Relaxed)
+    }
+    /// Requests answered with the default verdict because the handler panicked.
+    pub fn panicked(&self) -> u64 {
+        self.panicked.load(Ordering::Relaxed)
+    }
+    /// Requests whose response couldn't be written.
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+    /// Events that weren't permission events, or had no fd to answer.
+    pub fn ignored(&self) -> u64 {
+        self.ignored.load(Ordering::Relaxed)
+    }
+    /// Requests received and not answered yet.
+    pub fn pending(&self) -> u64 {
+        self.received()
+            .saturating_sub(self.answered() + self.timed_out() + self.panicked() + self.failed())
+    }
+}
+
+// shared between the worker holding the request and the watchdog, whoever takes the lock
+// first answers. The worker keeps the event fd open until it has checked the flag, so the
+// watchdog never answers for an fd number that was closed and reused.
+struct Deadline {
+    answered: Mutex<bool>,
+    fd: RawFd,
+    at: Instant,
+}
+
+impl PermissionDispatcher {
+    pub const DEFAULT_QUEUE_SIZE: usize = 64;
+
+    /// Dispatches the permission events of `fan`, answering `default` when no verdict is made
+    /// in time. A [`ShutdownHandle`] is attached to the group unless it already has one.
+    pub fn new(fan: Fanotify<OwnedFd>, default: Verdict) -> std::io::Result<Self> {
+        let fan = match fan.shutdown_handle() {
+            Some(_) => fan,
+            None => fan.with_shutdown_handle(ShutdownHandle::new()?),
+        };
+        let shutdown = fan.shutdown_handle().cloned().unwrap();
+        let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Ok(Self {
+            fan: Arc::new(fan),
+            shutdown,
+            default,
+            workers,
+            queue_size: Self::DEFAULT_QUEUE_SIZE,
+            deadline: None,
+            cache: None,
+            stats: Arc::new(DispatcherStats::default()),
+        })
+    }
+
+    /// Number of worker threads, the available parallelism by default.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Requests waiting for a worker before the reader stops reading.
+    pub fn queue_size(mut self, queue_size: usize) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Time from reading a request to answering it with the default verdict. Without a
+    /// deadline, requests wait for the handler however long it takes.
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Answers requests for cached files without asking the handler, and caches the allow
+    /// verdicts the handler reports as cacheable, see [`PermissionHandler::response`].
+    pub fn verdict_cache(mut self, cache: VerdictCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// The cache, e.g. to invalidate files while running.
+    pub fn cache(&self) -> Option<&VerdictCache> {
+        self.cache.as_ref()
+    }
+
+    /// The group requests are read from, marks can be added while it runs.
+    pub fn fanotify(&self) -> &Fanotify<OwnedFd> {
+        &self.fan
+    }
+
+    /// Stops [`PermissionDispatcher::run`] once the queued requests are answered.
+    pub fn shutdown_handle(&self) -> &ShutdownHandle {
+        &self.shutdown
+    }
+
+    pub fn stats(&self) -> Arc<DispatcherStats> {
+        self.stats.clone()
+    }
+
+    /// Reads and dispatches events until the shutdown handle is triggered or reading fails.
+    ///
+    /// `handler` is a [`Policy`](crate::policy::Policy) or any other [`PermissionHandler`],
+    /// like a closure `|request: &PermissionRequest| Verdict::Allow`.
+    pub fn run<H>(&self, handler: H) -> std::io::Result<()>
+    where
+        H: PermissionHandler + Sync,
+    {
+        let (queue, requests) = mpsc::sync_channel(self.queue_size);
+        let requests = Mutex::new(requests);
+        let (deadlines, watched) = mpsc::channel();
+
+        std::thread::scope(|scope| {
+            for _ in 0..self.workers {
+                scope.spawn(|| loop {
+                    // the lock is only held while waiting, not while handling
+                    let next = requests.lock().unwrap().recv();
+                    let Ok((request, deadline)) = next else {
+                        break;
+                    };
+                    self.handle(request, deadline, &handler);
+                });
+            }
+            if self.deadline.is_some() {
+                scope.spawn(|| self.watch(watched));
+            }
+
+            // dropping the senders at the end lets workers and watchdog finish
+            self.read(queue, deadlines)
+        })
+    }
+
+    fn read(
+        &self,
+        queue: mpsc::SyncSender<(PermissionRequest, Option<Arc<Deadline>>)>,
+        deadlines: mpsc::Sender<Arc<Deadline>>,
+    ) -> std::io::Result<()> {
+        let mut buffer = EventBuffer::new();
+        loop {
+            if !wait_readable(self.fan.as_fd(), Some(&self.shutdown), None)? {
+                return Ok(());
+            }
+            let nread = match (&*self.fan).read(buffer.as_mut_slice()) {
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => continue,
+                result => result?,
+            };
+
+            let mut events = buffer.events(nread);
+            while let Some(event) = events.next() {
+                let event = match event.map(EventRef::try_to_event) {
+                    Ok(Ok(event)) => event,
+                    // the records after a malformed header can't be found
+                    Err(_) => {
+                        self.stats.failed.fetch_add(1, Ordering::Relaxed);
+                        break;
+                    }
+                    // the header is intact, a permission event can still be answered
+                    Ok(Err((event, _))) => {
+                        if event.mask().is_permission_event() {
+                            if let Some(fd) = event.fd() {
+                                let response = Response::from_verdict(fd, self.default);
+                                let _ = (&*self.fan).write_all(response.as_bytes());
+                            }
+                        }
+                        self.stats.failed.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                };
+                if !self.dispatch(event, &queue, &deadlines) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // false once the workers are gone
+    fn dispatch(
+        &self,
+        event: Event,
+        queue: &mpsc::SyncSender<(PermissionRequest, Option<Arc<Deadline>>)>,
+        deadlines: &mpsc::Sender<Arc<Deadline>>,
+    ) -> bool {
+        if !event.mask().is_permission_event() || event.fd().is_none() {
+            self.stats.ignored.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+        // answered with the default verdict if it fails
+        let Ok(request) = event.into_permission_request(&*self.fan, self.default) else {
+            self.stats.failed.fetch_add(1, Ordering::Relaxed);
+            return true;
+        };
+        let deadline = self.deadline.map(|deadline| {
+            Arc::new(Deadline {
+                answered: Mutex::new(false),
+                fd: request.fd().as_raw_fd(),
+                at: Instant::now() + deadline,
+            })
+        });
+        self.stats.received.fetch_add(1, Ordering::Relaxed);
+        // blocks while the queue is full. If it fails, the request is answered on drop, and
+        // the watchdog must not know its fd
+        if queue.send((request, deadline.clone())).is_err() {
+            return false;
+        }
+        if let Some(deadline) = deadline {
+            let _ = deadlines.send(deadline);
+        }
+        true
+    }
+
+    fn handle<H>(&self, request: PermissionRequest, deadline: Option<Arc<Deadline>>, handler: &H)
+    where
+        H: PermissionHandler,
+    {
+        if deadline
+            .as_ref()
+            .is_some_and(|deadline| *deadline.answered.lock().unwrap())
+        {
+            request.dismiss();
+            return;
+        }
+
+        let (response, cacheable, counter) = if self
+            .cache
+            .as_ref()
+            .is_some_and(|cache| cache.lookup(&*self.fan, &request))
+        {
+            (
+                request.response(Verdict::Allow),
+                false,
+                &self.stats.answered,
+            )
+        } else {
+            // a panicking handler must not close the event fd behind the watchdog's back
+            let decision = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                let (response, cacheable) = handler.response(&request);
+                let cacheable =
+                    cacheable && self.cache.is_some() && response.verdict() == Verdict::Allow;
+                (response, cacheable)
+            }));
+            match decision {
+                Ok((response, cacheable)) => (response, cacheable, &self.stats.answered),
+                Err(_) => (request.response(self.default), false, &self.stats.panicked),
+            }
+        };
+
+        match deadline {
+            Some(deadline) => {
+                let mut answered = deadline.answered.lock().unwrap();
+                if *answered {
+                    request.dismiss();
+                    return;
+                }
+                *answered = true;
+                self.respond(request, response, cacheable, counter);
+            }
+            None => self.respond(request, response, cacheable, counter),
+        }
+    }
+
+    fn respond(
+        &self,
+        request: PermissionRequest,
+        response: Response,
+        cacheable: bool,
+        counter: &AtomicU64,
+    ) {
+        // before answering, so the next access isn't asked for already
+        if let Some(cache) = self.cache.as_ref().filter(|_| cacheable) {
+            let _ = cache.insert(&*self.fan, &request);
+        }
+        match request.respond_with(response) {
+            Ok(()) => counter.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.stats.failed.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    // deadlines arrive in the order they expire, so waiting for the oldest is enough
+    fn watch(&self, watched: mpsc::Receiver<Arc<Deadline>>) {
+        let mut pending: VecDeque<Arc<Deadline>> = VecDeque::new();
+        let mut reading = true;
+        loop {
+            let timeout = pending
+                .front()
+                .map(|deadline| deadline.at.saturating_duration_since(Instant::now()));
+            let received = match timeout {
+                // nothing new arrives once the reader is done, but the queued requests
+                // still get their deadline
+                Some(timeout) if !reading => {
+                    std::thread::sleep(timeout);
+                    None
+                }
+                Some(timeout) => match watched.recv_timeout(timeout) {
+                    Ok(deadline) => Some(deadline),
+                    Err(mpsc::RecvTimeoutError::Timeout) => None,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        reading = false;
+                        None
+                    }
+                },
+                None if !reading => break,
+                None => match watched.recv() {
+                    Ok(deadline) => Some(deadline),
+                    Err(_) => {
+                        reading = false;
+                        None
+                    }
+                },
+            };
+            pending.extend(received);
+
+            let now = Instant::now();
+            while let Some(deadline) = pending.front() {
+                if *deadline.answered.lock().unwrap() {
+                    pending.pop_front();
+                } else if deadline.at <= now {
+                    self.expire(deadline);
+                    pending.pop_front();
+                } else {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn expire(&self, deadline: &Deadline) {
+        let mut answered = deadline.answered.lock().unwrap();
+        if *answered {
+            return;
+        }
+        *answered = true;
+        // the worker holding the request can't close the fd while the lock is held
+        let fd = unsafe { BorrowedFd::borrow_raw(deadline.fd) };
+        let response = Response::from_verdict(fd, self.default);
+        match (&*self.fan).write_all(response.as_bytes()) {
+            Ok(_) => self.stats.timed_out.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.stats.failed.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        io::{Read, Write},
+        os::{
+            fd::{IntoRawFd, OwnedFd, RawFd},
+            unix::net::UnixStream,
+        },
+        time::Duration,
+    };
+
+    use super::PermissionDispatcher;
+    use crate::{
+        consts::InitFlags,
+        fanotify::Fanotify,
+        messages::Verdict,
+        permission::{PermissionHandler, PermissionRequest},
+    };
+
+    const EVENT_SIZE: usize = size_of::<libc::fanotify_event_metadata>();
+
+    // a socket stands in for the group: events are written to the other end, and the
+    // responses come out there
+    fn dispatcher(default: Verdict) -> (PermissionDispatcher, UnixStream) {
+        let (group, kernel) = UnixStream::pair().unwrap();
+        let fan = Fanotify::new(OwnedFd::from(group), InitFlags::empty());
+        let dispatcher = PermissionDispatcher::new(fan, default).unwrap().workers(2);
+        (dispatcher, kernel)
+    }
+
+    fn event_fd() -> RawFd {
+        std::fs::File::open("/dev/null").unwrap().into_raw_fd()
+    }
+
+    fn event(fd: RawFd, info: &[u8]) -> Vec<u8> {
+        let metadata = libc::fanotify_event_metadata {
+            event_len: (EVENT_SIZE + info.len()) as u32,
+            vers: libc::FANOTIFY_METADATA_VERSION,
+            reserved: 0,
+            metadata_len: EVENT_SIZE as u16,
+            mask: libc::FAN_OPEN_PERM,
+            fd,
+            pid: 1,
+        };
+        let mut bytes = unsafe {
+            std::slice::from_raw_parts(
+                (&metadata as *const libc::fanotify_event_metadata).cast::<u8>(),
+                EVENT_SIZE,
+            )
+        }
+        .to_vec();
+        bytes.extend(info);
+        bytes
+    }
+
+    fn response(kernel: &mut UnixStream) -> (RawFd, u32) {
+        let mut response = [0u8; 8];
+        kernel.read_exact(&mut response).unwrap();
+        (
+            i32::from_ne_bytes(response[..4].try_into().unwrap()),
+            u32::from_ne_bytes(response[4..].try_into().unwrap()),
+        )
+    }
+
+    fn run<H: PermissionHandler + Sync>(
+        dispatcher: &PermissionDispatcher,
+        handler: H,
+        client: impl FnOnce() + Send,
+    ) {
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                client();
+                dispatcher.shutdown_handle().shutdown();
+            });
+            dispatcher.run(handler).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_deadline() {
+        let (dispatcher, mut kernel) = dispatcher(Verdict::Deny);
+        let dispatcher = dispatcher.deadline(Duration::from_millis(50));
+        let fd = event_fd();
+        let handler = |_: &PermissionRequest| {
+            std::thread::sleep(Duration::from_millis(300));
+            Verdict::Allow
+        };
+        run(&dispatcher, handler, || {
+            kernel.write_all(&event(fd, &[])).unwrap();
+            assert_eq!(response(&mut kernel), (fd, libc::FAN_DENY));
+        });
+
+        // the handler's late answer is dropped
+        kernel.set_nonblocking(true).unwrap();
+        assert_eq!(
+            kernel.read(&mut [0u8; 8]).unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
+        let stats = dispatcher.stats();
+        assert_eq!((stats.timed_out(), stats.answered()), (1, 0));
+    }
+
+    #[test]
+    fn test_panicking_handler() {
+        let (dispatcher, mut kernel) = dispatcher(Verdict::Deny);
+        let fd = event_fd();
+        let handler = |_: &PermissionRequest| -> Verdict { panic!("handler failed") };
+        run(&dispatcher, handler, || {
+            kernel.write_all(&event(fd, &[])).unwrap();
+            assert_eq!(response(&mut kernel), (fd, libc::FAN_DENY));
+        });
+        assert_eq!(dispatcher.stats().panicked(), 1);
+    }
+
+    #[test]
+    fn test_malformed_events() {
+        let (dispatcher, mut kernel) = dispatcher(Verdict::Deny);
+        let (good, bad_info, later) = (event_fd(), event_fd(), event_fd());
+        let handler = |_: &PermissionRequest| Verdict::Allow;
+        run(&dispatcher, handler, || {
+            let mut bytes = event(good, &[]);
+            // an info record longer than the event
+            bytes.extend(event(bad_info, &[1, 0, 100, 0]));
+            // a header too short to find the next record
+            bytes.extend([0u8; 8]);
+            kernel.write_all(&bytes).unwrap();
+
+            let responses: HashMap<_, _> = [response(&mut kernel), response(&mut kernel)].into();
+            assert_eq!(responses[&good], libc::FAN_ALLOW);
+            assert_eq!(responses[&bad_info], libc::FAN_DENY);
+
+            // reading goes on
+            kernel.write_all(&event(later, &[])).unwrap();
+            assert_eq!(response(&mut kernel), (later, libc::FAN_ALLOW));
+        });
+        assert_eq!(dispatcher.stats().failed(), 2);
+    }
+}
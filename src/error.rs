@@ -1,8 +1,12 @@
 use std::{
     error::Error,
     fmt::{Debug, Display},
+    path::PathBuf,
 };
 
+use crate::consts::{EventFFlags, InitFlags, MarkFlags, MaskFlags};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Errno {
     raw_errno: i32,
 }
@@ -11,6 +15,10 @@ impl Errno {
     pub fn new(errno: i32) -> Self {
         Self { raw_errno: errno }
     }
+    pub fn raw_errno(&self) -> i32 {
+        self.raw_errno
+    }
+    #[allow(clippy::self_named_constructors)]
     pub fn errno() -> Self {
         Self::new(unsafe { *libc::__errno_location() })
     }
@@ -28,14 +36,7 @@ impl Display for Errno {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let str = unsafe {
             let cstr = libc::strerror(self.raw_errno);
-            let len = libc::strlen(cstr);
-
-            let mut buffer = Vec::with_capacity(len);
-            buffer.set_len(len);
-
-            libc::strncpy(buffer.as_mut_ptr() as *mut i8, cstr, len);
-
-            String::from_utf8(buffer)
+            std::ffi::CStr::from_ptr(cstr).to_str().map(str::to_owned)
         };
         let result = if let Ok(str) = str {
             f.write_str(&str)
@@ -57,9 +58,232 @@ impl Debug for Errno {
 
 impl Error for Errno {}
 
+/// Error returned when the bytes read from a fanotify fd do not form valid event records.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    #[error("truncated record at offset {offset}: {needed} bytes needed, {available} available")]
+    Truncated {
+        offset: usize,
+        needed: usize,
+        available: usize,
+    },
+
+    #[error("bad record length {len} at offset {offset}")]
+    BadLength { offset: usize, len: usize },
+
+    #[error("unsupported fanotify metadata version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("unknown event info type {info_type} at offset {offset}")]
+    UnknownInfoType { offset: usize, info_type: u8 },
+}
+
+impl From<ParseError> for std::io::Error {
+    fn from(value: ParseError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, value)
+    }
+}
+
+/// Flags and path of the call that failed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ErrorContext {
+    pub init_flags: Option<InitFlags>,
+    pub event_f_flags: Option<EventFFlags>,
+    pub mark_flags: Option<MarkFlags>,
+    pub mask: Option<MaskFlags>,
+    pub path: Option<PathBuf>,
+}
+
+impl ErrorContext {
+    pub(crate) fn init(init_flags: InitFlags, event_f_flags: EventFFlags) -> Self {
+        Self {
+            init_flags: Some(init_flags),
+            event_f_flags: Some(event_f_flags),
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn mark(mark_flags: MarkFlags, mask: MaskFlags, path: Option<PathBuf>) -> Self {
+        Self {
+            mark_flags: Some(mark_flags),
+            mask: Some(mask),
+            path,
+            ..Self::default()
+        }
+    }
+}
+
+impl Display for ErrorContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut fields = Vec::new();
+        if let Some(flags) = self.init_flags {
+            fields.push(format!("init flags {flags:?}"));
+        }
+        if let Some(flags) = self.event_f_flags {
+            fields.push(format!("event fd flags {flags:?}"));
+        }
+        if let Some(flags) = self.mark_flags {
+            fields.push(format!("mark flags {flags:?}"));
+        }
+        if let Some(mask) = self.mask {
+            fields.push(format!("mask {mask:?}"));
+        }
+        if let Some(path) = &self.path {
+            fields.push(format!("path {path:?}"));
+        }
+        f.write_str(&fields.join(", "))
+    }
+}
+
+/// Error of `fanotify_init(2)` and `fanotify_mark(2)`, with the usual causes told apart.
+#[derive(thiserror::Error, Debug)]
+pub enum FanotifyError {
+    // EPERM
+    #[error("CAP_SYS_ADMIN is required: {errno} ({context})")]
+    MissingCapability { errno: Errno, context: ErrorContext },
+
+    // EINVAL, usually a flag the running kernel doesn't know or a combination it refuses
+    #[error("unsupported flags: {errno} ({context})")]
+    Unsupported { errno: Errno, context: ErrorContext },
+
+    // ENOSPC, the mark limit of the user or of the group without FAN_UNLIMITED_MARKS
+    #[error("too many marks: {errno} ({context})")]
+    TooManyMarks { errno: Errno, context: ErrorContext },
+
+    // EMFILE, the group limit of the user or the fd limit of the process
+    #[error("too many fanotify groups or open files: {errno} ({context})")]
+    TooManyGroups { errno: Errno, context: ErrorContext },
+
+    // EOPNOTSUPP, EXDEV or ENODEV while marking with FAN_REPORT_FID and friends
+    #[error("the filesystem doesn't support file handles: {errno} ({context})")]
+    FileHandlesUnsupported { errno: Errno, context: ErrorContext },
+
+    // ENOTDIR with FAN_MARK_ONLYDIR
+    #[error("not a directory: {errno} ({context})")]
+    NotADirectory { errno: Errno, context: ErrorContext },
+
+    // refused up front by a group created with init_unprivileged
+    #[error("{what} requires CAP_SYS_ADMIN, the group is unprivileged ({context})")]
+    RequiresPrivilege {
+        what: &'static str,
+        context: ErrorContext,
+    },
+
+    // refused before calling into the kernel
+    #[error("invalid argument: {reason} ({context})")]
+    InvalidArgument {
+        reason: &'static str,
+        context: ErrorContext,
+    },
+
+    #[error("io error: {source} ({context})")]
+    Io {
+        source: std::io::Error,
+        context: ErrorContext,
+    },
+}
+
+impl FanotifyError {
+    pub(crate) fn from_init(
+        error: std::io::Error,
+        init_flags: InitFlags,
+        event_f_flags: EventFFlags,
+    ) -> Self {
+        Self::from_io(error, ErrorContext::init(init_flags, event_f_flags), false)
+    }
+
+    // `fid` tells whether the group reports file handles
+    pub(crate) fn from_mark(
+        error: std::io::Error,
+        mark_flags: MarkFlags,
+        mask: MaskFlags,
+        path: Option<PathBuf>,
+        fid: bool,
+    ) -> Self {
+        Self::from_io(error, ErrorContext::mark(mark_flags, mask, path), fid)
+    }
+
+    fn from_io(error: std::io::Error, context: ErrorContext, fid: bool) -> Self {
+        let Some(raw_errno) = error.raw_os_error() else {
+            return FanotifyError::Io {
+                source: error,
+                context,
+            };
+        };
+        let errno = Errno::new(raw_errno);
+        match raw_errno {
+            libc::EPERM => FanotifyError::MissingCapability { errno, context },
+            libc::EINVAL => FanotifyError::Unsupported { errno, context },
+            libc::ENOSPC => FanotifyError::TooManyMarks { errno, context },
+            libc::EMFILE => FanotifyError::TooManyGroups { errno, context },
+            libc::EOPNOTSUPP | libc::EXDEV | libc::ENODEV if fid => {
+                FanotifyError::FileHandlesUnsupported { errno, context }
+            }
+            libc::ENOTDIR
+                if context
+                    .mark_flags
+                    .is_some_and(|flags| flags.contains(MarkFlags::FAN_MARK_ONLYDIR)) =>
+            {
+                FanotifyError::NotADirectory { errno, context }
+            }
+            _ => FanotifyError::Io {
+                source: error,
+                context,
+            },
+        }
+    }
+
+    pub(crate) fn invalid_argument(reason: &'static str, context: ErrorContext) -> Self {
+        FanotifyError::InvalidArgument { reason, context }
+    }
+
+    pub fn errno(&self) -> Option<Errno> {
+        match self {
+            FanotifyError::MissingCapability { errno, .. }
+            | FanotifyError::Unsupported { errno, .. }
+            | FanotifyError::TooManyMarks { errno, .. }
+            | FanotifyError::TooManyGroups { errno, .. }
+            | FanotifyError::FileHandlesUnsupported { errno, .. }
+            | FanotifyError::NotADirectory { errno, .. } => Some(*errno),
+            FanotifyError::RequiresPrivilege { .. } => Some(Errno::new(libc::EPERM)),
+            FanotifyError::InvalidArgument { .. } => Some(Errno::new(libc::EINVAL)),
+            FanotifyError::Io { source, .. } => source.raw_os_error().map(Errno::new),
+        }
+    }
+
+    pub fn context(&self) -> &ErrorContext {
+        match self {
+            FanotifyError::MissingCapability { context, .. }
+            | FanotifyError::Unsupported { context, .. }
+            | FanotifyError::TooManyMarks { context, .. }
+            | FanotifyError::TooManyGroups { context, .. }
+            | FanotifyError::FileHandlesUnsupported { context, .. }
+            | FanotifyError::NotADirectory { context, .. }
+            | FanotifyError::RequiresPrivilege { context, .. }
+            | FanotifyError::InvalidArgument { context, .. }
+            | FanotifyError::Io { context, .. } => context,
+        }
+    }
+}
+
+impl From<FanotifyError> for std::io::Error {
+    fn from(value: FanotifyError) -> Self {
+        let kind = match &value {
+            FanotifyError::InvalidArgument { .. } => std::io::ErrorKind::InvalidInput,
+            FanotifyError::Io { source, .. } => source.kind(),
+            error => match error.errno() {
+                Some(errno) => std::io::Error::from_raw_os_error(errno.raw_errno()).kind(),
+                None => std::io::ErrorKind::Other,
+            },
+        };
+        std::io::Error::new(kind, value)
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::Errno;
+    use super::{Errno, FanotifyError};
+    use crate::consts::{MarkFlags, MaskFlags};
 
     #[test]
     fn test_errno() {
@@ -69,4 +293,35 @@ mod test {
         );
         assert_eq!(Errno::new(0).to_string(), "Success");
     }
+
+    #[test]
+    fn test_fanotify_error() {
+        let mark = |errno, flags, fid| {
+            FanotifyError::from_mark(
+                std::io::Error::from_raw_os_error(errno),
+                MarkFlags::FAN_MARK_ADD | flags,
+                MaskFlags::FAN_OPEN,
+                Some("/tmp".into()),
+                fid,
+            )
+        };
+        assert!(matches!(
+            mark(libc::EXDEV, MarkFlags::empty(), true),
+            FanotifyError::FileHandlesUnsupported { .. }
+        ));
+        assert!(matches!(
+            mark(libc::EXDEV, MarkFlags::empty(), false),
+            FanotifyError::Io { .. }
+        ));
+        assert!(matches!(
+            mark(libc::ENOTDIR, MarkFlags::FAN_MARK_ONLYDIR, false),
+            FanotifyError::NotADirectory { .. }
+        ));
+
+        let error = mark(libc::EPERM, MarkFlags::FAN_MARK_MOUNT, false);
+        assert_eq!(error.errno(), Some(Errno::new(libc::EPERM)));
+        assert_eq!(error.context().path.as_deref(), Some("/tmp".as_ref()));
+        let error: std::io::Error = error.into();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+    }
 }
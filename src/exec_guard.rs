@@ -0,0 +1,349 @@
+use std::{
+    collections::{HashMap, HashSet},
+    os::fd::{AsRawFd, BorrowedFd, OwnedFd},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    consts::{EventFFlags, InitFlags, MarkFlags, MaskFlags},
+    dispatcher::PermissionDispatcher,
+    error::{ErrorContext, FanotifyError},
+    fanotify::Fanotify,
+    mark::MarkTarget,
+    messages::{Response, Verdict},
+    permission::{PermissionHandler, PermissionRequest},
+};
+
+/// SHA-256 of a file's content.
+pub type Hash = [u8; 32];
+
+/// Executables allowed to run, by content hash, optionally only at a given path.
+#[derive(Clone, Debug, Default)]
+pub struct Allowlist {
+    anywhere: HashSet<Hash>,
+    at: HashMap<PathBuf, HashSet<Hash>>,
+}
+
+impl Allowlist {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads an allowlist in `sha256sum` format, e.g. made with `sha256sum /usr/bin/*`.
+    ///
+    /// A line is a hex hash and an absolute path, allowing that content at that path, or a
+    /// hash alone, allowing it anywhere. Empty lines and lines starting with `#` are skipped.
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// Allows `hash` at any path.
+    pub fn allow(&mut self, hash: Hash) {
+        self.anywhere.insert(hash);
+    }
+
+    /// Allows `hash` only when executed from `path`.
+    pub fn allow_at<P: Into<PathBuf>>(&mut self, hash: Hash, path: P) {
+        self.at.entry(path.into()).or_default().insert(hash);
+    }
+
+    pub fn allows(&self, hash: &Hash, path: Option<&Path>) -> bool {
+        self.anywhere.contains(hash)
+            || path
+                .and_then(|path| self.at.get(path))
+                .is_some_and(|hashes| hashes.contains(hash))
+    }
+
+    pub fn len(&self) -> usize {
+        self.anywhere.len() + self.at.values().map(HashSet::len).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl std::str::FromStr for Allowlist {
+    type Err = std::io::Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut allowlist = Allowlist::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: &str| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("line {}: {reason}", number + 1),
+                )
+            };
+            let (hash, path) = match line.is_char_boundary(64) {
+                true => line.split_at(64),
+                false => (line, ""),
+            };
+            let hash = parse_hex(hash).ok_or_else(|| invalid("not a sha256 hash"))?;
+            if path.is_empty() {
+                allowlist.allow(hash);
+                continue;
+            }
+            // sha256sum separates with two spaces, or a space and `*` in binary mode
+            let path = path
+                .strip_prefix("  ")
+                .or_else(|| path.strip_prefix(" *"))
+                .ok_or_else(|| invalid("expected two spaces after the hash"))?;
+            if !path.starts_with('/') {
+                return Err(invalid("path is not absolute"));
+            }
+            allowlist.allow_at(hash, path);
+        }
+        Ok(allowlist)
+    }
+}
+
+/// Hashes the content of `fd` from the start, without moving its file offset.
+pub fn hash_fd(fd: BorrowedFd) -> std::io::Result<Hash> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut offset = 0;
+    loop {
+        let nread = unsafe {
+            libc::pread(
+                fd.as_raw_fd(),
+                buffer.as_mut_ptr().cast(),
+                buffer.len(),
+                offset,
+            )
+        };
+        match nread {
+            0 => return Ok(hasher.finalize().into()),
+            -1 => {
+                let error = std::io::Error::last_os_error();
+                if error.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(error);
+                }
+            }
+            nread => {
+                hasher.update(&buffer[..nread as usize]);
+                offset += nread as libc::off_t;
+            }
+        }
+    }
+}
+
+/// Application allowlisting: executables run only if their content is on an [`Allowlist`].
+///
+/// Answers `FAN_OPEN_EXEC_PERM` requests with the SHA-256 of the file behind the event fd, and
+/// every response carries `FAN_AUDIT`, so the group needs `FAN_ENABLE_AUDIT` as made by
+/// [`ExecGuard::dispatcher`]. Files that can't be hashed get the default verdict, see
+/// [`ExecGuard::default_verdict`]. Other permission events are allowed. In monitor only mode,
+/// executables missing from the allowlist are logged and allowed.
+///
+/// Clones share the allowlist, so one can be kept to replace it while another runs.
+///
+/// ```no_run
+/// use fanotify::exec_guard::{Allowlist, ExecGuard};
+/// use fanotify::mark::MarkTarget;
+///
+/// let guard = ExecGuard::new(Allowlist::load("/etc/exec-allowlist")?).monitor_only();
+/// let dispatcher = guard.dispatcher()?;
+/// ExecGuard::mark(dispatcher.fanotify(), MarkTarget::Filesystem, "/")?;
+/// dispatcher.run(guard.clone())?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Clone)]
+pub struct ExecGuard {
+    allowlist: Arc<RwLock<Allowlist>>,
+    monitor_only: bool,
+}
+
+impl ExecGuard {
+    pub fn new(allowlist: Allowlist) -> Self {
+        Self {
+            allowlist: Arc::new(RwLock::new(allowlist)),
+            monitor_only: false,
+        }
+    }
+
+    /// Logs the executables that would be denied, and allows them.
+    pub fn monitor_only(mut self) -> Self {
+        self.monitor_only = true;
+        self
+    }
+
+    pub fn is_monitor_only(&self) -> bool {
+        self.monitor_only
+    }
+
+    /// Replaces the allowlist, for every clone of this guard.
+    pub fn set_allowlist(&self, allowlist: Allowlist) {
+        *self.allowlist.write().unwrap() = allowlist;
+    }
+
+    /// Verdict for requests that couldn't be answered in time, deny unless monitor only.
+    pub fn default_verdict(&self) -> Verdict {
+        if self.monitor_only {
+            Verdict::Allow
+        } else {
+            Verdict::Deny
+        }
+    }
+
+    /// A group for the guard, with `FAN_ENABLE_AUDIT`, answering the default verdict when a
+    /// request can't be answered otherwise. Marks are added with [`ExecGuard::mark`].
+    pub fn dispatcher(&self) -> std::io::Result<PermissionDispatcher> {
+        let fan = Fanotify::<OwnedFd>::init(
+            InitFlags::FAN_CLASS_CONTENT | InitFlags::FAN_CLOEXEC | InitFlags::FAN_ENABLE_AUDIT,
+            EventFFlags::O_RDONLY | EventFFlags::O_LARGEFILE | EventFFlags::O_CLOEXEC,
+        )?;
+        PermissionDispatcher::new(fan, self.default_verdict())
+    }
+
+    /// Guards the executions on the mount or filesystem `path` is on, `target` must be
+    /// [`MarkTarget::Mount`] or [`MarkTarget::Filesystem`].
+    pub fn mark<P: AsRef<Path>>(
+        fan: &Fanotify<OwnedFd>,
+        target: MarkTarget,
+        path: P,
+    ) -> Result<(), FanotifyError> {
+        let operation = MarkFlags::FAN_MARK_ADD | target.flags();
+        let mask = MaskFlags::FAN_OPEN_EXEC_PERM;
+        if !matches!(target, MarkTarget::Mount | MarkTarget::Filesystem) {
+            return Err(FanotifyError::invalid_argument(
+                "executions are guarded on mounts and filesystems only",
+                ErrorContext::mark(operation, mask, Some(path.as_ref().to_owned())),
+            ));
+        }
+        fan.mark(operation, mask, None, Some(path.as_ref()))
+    }
+
+    fn check(&self, request: &PermissionRequest) -> Verdict {
+        let fd = request.fd();
+        let path = std::fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd())).ok();
+        let display = path.as_deref().unwrap_or(Path::new("?")).display();
+        let hash = match hash_fd(fd) {
+            Ok(hash) => hash,
+            Err(error) => {
+                log::warn!(
+                    "pid {} executing {display}: can't hash: {error}",
+                    request.pid()
+                );
+                return self.default_verdict();
+            }
+        };
+
+        if self
+            .allowlist
+            .read()
+            .unwrap()
+            .allows(&hash, path.as_deref())
+        {
+            log::debug!("pid {} executing {display}: allowed", request.pid());
+            return Verdict::Allow;
+        }
+        let action = if self.monitor_only {
+            "not on the allowlist, monitor only"
+        } else {
+            "denied"
+        };
+        log::warn!(
+            "pid {} executing {display} ({}): {action}",
+            request.pid(),
+            to_hex(&hash),
+        );
+        self.default_verdict()
+    }
+}
+
+impl PermissionHandler for ExecGuard {
+    fn decide(&self, request: &PermissionRequest) -> Verdict {
+        if request
+            .event()
+            .mask()
+            .contains(MaskFlags::FAN_OPEN_EXEC_PERM)
+        {
+            self.check(request)
+        } else {
+            Verdict::Allow
+        }
+    }
+
+    fn response(&self, request: &PermissionRequest) -> (Response, bool) {
+        (request.response(self.decide(request)).with_audit(), false)
+    }
+}
+
+fn parse_hex(hex: &str) -> Option<Hash> {
+    let hex = hex.as_bytes();
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut hash = [0u8; 32];
+    for (byte, pair) in hash.iter_mut().zip(hex.chunks(2)) {
+        let pair = std::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(hash)
+}
+
+fn to_hex(hash: &Hash) -> String {
+    hash.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        os::fd::{AsFd, OwnedFd},
+        path::Path,
+    };
+
+    use super::{hash_fd, parse_hex, to_hex, Allowlist, ExecGuard};
+    use crate::{consts::InitFlags, error::FanotifyError, fanotify::Fanotify, mark::MarkTarget};
+
+    const EMPTY: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn test_hash_fd() {
+        let file = std::fs::File::open("/dev/null").unwrap();
+        assert_eq!(to_hex(&hash_fd(file.as_fd()).unwrap()), EMPTY);
+    }
+
+    #[test]
+    fn test_parse_allowlist() {
+        let hash = parse_hex(EMPTY).unwrap();
+        let other = [1u8; 32];
+        let text = format!("# comment\n\n{EMPTY}  /usr/bin/true\n{EMPTY} */usr/bin/false\n");
+        let allowlist: Allowlist = text.parse().unwrap();
+        assert_eq!(allowlist.len(), 2);
+        assert!(allowlist.allows(&hash, Some(Path::new("/usr/bin/true"))));
+        assert!(allowlist.allows(&hash, Some(Path::new("/usr/bin/false"))));
+        assert!(!allowlist.allows(&hash, Some(Path::new("/tmp/true"))));
+        assert!(!allowlist.allows(&other, Some(Path::new("/usr/bin/true"))));
+        assert!(!allowlist.allows(&hash, None));
+
+        let anywhere: Allowlist = EMPTY.parse().unwrap();
+        assert!(anywhere.allows(&hash, None));
+
+        assert!(format!("{EMPTY}  relative").parse::<Allowlist>().is_err());
+        assert!("abc  /usr/bin/true".parse::<Allowlist>().is_err());
+    }
+
+    #[test]
+    fn test_mark_targets() {
+        // refused before fanotify_mark(2), any fd stands in for the group
+        let fan = Fanotify::new(
+            OwnedFd::from(std::fs::File::open("/dev/null").unwrap()),
+            InitFlags::empty(),
+        );
+        for target in [MarkTarget::Inode, MarkTarget::MountNamespace] {
+            assert!(matches!(
+                ExecGuard::mark(&fan, target, "/"),
+                Err(FanotifyError::InvalidArgument { .. })
+            ));
+        }
+    }
+}
@@ -1,65 +1,351 @@
 use std::{
     ffi::CString,
     io::{Read, Write},
-    os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::Path,
     ptr::null,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
-use crate::{consts::{EventFFlags, InitFlags, MarkFlags, MaskFlags}, messages::{Event, Response}};
+use crate::{
+    consts::{EventFFlags, InitFlags, MarkFlags, MaskFlags},
+    error::{Errno, ErrorContext, FanotifyError},
+    mark::{registry_path, MarkRegistry},
+    messages::{Event, EventBuffer, EventIter, Response},
+    resolver::HandleResolver,
+    shutdown::{wait_readable, ShutdownHandle},
+};
 
 pub struct Fanotify<F> {
     pub(crate) fd: F,
+    pub(crate) resolver: Option<Arc<HandleResolver>>,
+    pub(crate) registry: Option<Mutex<MarkRegistry>>,
+    pub(crate) init_flags: InitFlags,
+    pub(crate) unprivileged: bool,
+    pub(crate) shutdown: Option<ShutdownHandle>,
+}
+
+impl<F> Fanotify<F> {
+    pub(crate) fn new(fd: F, init_flags: InitFlags) -> Self {
+        Self {
+            fd,
+            resolver: None,
+            registry: None,
+            init_flags,
+            unprivileged: false,
+            shutdown: None,
+        }
+    }
+
+    /// Flags the group was initialized with.
+    pub fn init_flags(&self) -> InitFlags {
+        self.init_flags
+    }
+
+    /// Whether the group was created by `init_unprivileged`, and refuses marks that need
+    /// `CAP_SYS_ADMIN` before asking the kernel.
+    pub fn is_unprivileged(&self) -> bool {
+        self.unprivileged
+    }
+
+    /// Registers the filesystem of every path marked from now on with `resolver`,
+    /// so file handles reported by `FAN_REPORT_FID` can be opened later.
+    pub fn with_handle_resolver(mut self, resolver: Arc<HandleResolver>) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    pub fn handle_resolver(&self) -> Option<&Arc<HandleResolver>> {
+        self.resolver.as_ref()
+    }
+
+    /// Records every mark added or removed from now on, see [`Fanotify::marks`].
+    pub fn with_mark_registry(mut self) -> Self {
+        self.registry = Some(Mutex::new(MarkRegistry::default()));
+        self
+    }
+
+    /// Lets `shutdown` wake this group's blocking reads, see [`ShutdownHandle`].
+    pub fn with_shutdown_handle(mut self, shutdown: ShutdownHandle) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    pub fn shutdown_handle(&self) -> Option<&ShutdownHandle> {
+        self.shutdown.as_ref()
+    }
 }
 
 impl Fanotify<OwnedFd> {
-    pub fn init(init_flags: InitFlags, event_fd_flags: EventFFlags) -> std::io::Result<Self> {
+    pub fn init(init_flags: InitFlags, event_fd_flags: EventFFlags) -> Result<Self, FanotifyError> {
         Self::try_init(init_flags, event_fd_flags)
     }
-    pub fn try_init(init_flags: InitFlags, event_fd_flags: EventFFlags) -> std::io::Result<Self> {
+    pub fn try_init(
+        init_flags: InitFlags,
+        event_fd_flags: EventFFlags,
+    ) -> Result<Self, FanotifyError> {
         let fd = unsafe {
             let ret = libc::fanotify_init(init_flags.bits(), event_fd_flags.bits());
             if ret == -1 {
-                return Err(std::io::Error::last_os_error());
+                return Err(FanotifyError::from_init(
+                    std::io::Error::last_os_error(),
+                    init_flags,
+                    event_fd_flags,
+                ));
             }
             OwnedFd::from_raw_fd(ret)
         };
-        Ok(Self { fd })
+        Ok(Self::new(fd, init_flags))
+    }
+
+    /// Creates a group without `CAP_SYS_ADMIN`, Linux 5.13.
+    ///
+    /// Unprivileged groups are notification groups reporting file handles, `init_flags` must
+    /// contain `FAN_REPORT_FID` or `FAN_REPORT_DIR_FID`. They get inode marks only, no permission
+    /// events, no mount events, no pidfds and thread ids, and a limited queue and number of marks.
+    /// Flags outside of that are refused with [`FanotifyError::RequiresPrivilege`] instead of a
+    /// bare `EPERM`.
+    pub fn init_unprivileged(
+        init_flags: InitFlags,
+        event_fd_flags: EventFFlags,
+    ) -> Result<Self, FanotifyError> {
+        check_unprivileged(init_flags, event_fd_flags)?;
+        let mut fan = Self::init(init_flags, event_fd_flags)?;
+        fan.unprivileged = true;
+        Ok(fan)
     }
 
+    /// Blocks until events arrive, unless the group was initialized with `FAN_NONBLOCK`.
+    /// Returns no events once the [`ShutdownHandle`] is triggered.
+    ///
+    /// A malformed record is logged, the events around it are returned as
+    /// [`Event::extract_all`] keeps them. The read fails only if none could be parsed.
     pub fn read_events(&mut self) -> std::io::Result<Vec<Event>> {
+        if self.shutdown.is_some() && !self.wait_readable(None)? {
+            return Ok(Vec::new());
+        }
+        self.read_events_once()
+    }
+
+    /// Waits at most `timeout` for events with `poll(2)`, no events are returned when it
+    /// passes or the [`ShutdownHandle`] is triggered. Works for blocking and `FAN_NONBLOCK`
+    /// groups alike.
+    pub fn read_events_timeout(&mut self, timeout: Duration) -> std::io::Result<Vec<Event>> {
+        if !self.wait_readable(Some(timeout))? {
+            return Ok(Vec::new());
+        }
+        match self.read_events_once() {
+            // another reader of the same group was faster
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => Ok(Vec::new()),
+            result => result,
+        }
+    }
+
+    fn wait_readable(&self, timeout: Option<Duration>) -> std::io::Result<bool> {
+        wait_readable(self.fd.as_fd(), self.shutdown.as_ref(), timeout)
+    }
+
+    /// Creates a group reporting `FAN_FS_ERROR` for the whole filesystem `path` lives on.
+    pub fn init_fs_error_monitor<P: AsRef<Path>>(path: P) -> Result<Self, FanotifyError> {
+        Self::init_fs_error_monitor_with(path, InitFlags::empty())
+    }
+
+    pub(crate) fn init_fs_error_monitor_with<P: AsRef<Path>>(
+        path: P,
+        init_flags: InitFlags,
+    ) -> Result<Self, FanotifyError> {
+        let fan = Self::init(
+            InitFlags::FS_ERROR_MONITOR | init_flags,
+            EventFFlags::O_RDONLY,
+        )?;
+        fan.mark_fs_errors(path)?;
+        Ok(fan)
+    }
+
+    // The async backends are a readiness type around a FAN_NONBLOCK group, made with
+    // into_wrapped. Once their fd is readable they call read_events_once or read through
+    // &Fanotify, and answer with respond, like the blocking group does.
+
+    #[cfg(any(feature = "aio", feature = "async-io"))]
+    pub(crate) fn into_wrapped<W>(
+        self,
+        wrap: impl FnOnce(Self) -> std::io::Result<W>,
+    ) -> Result<Fanotify<W>, FanotifyError> {
+        let (init_flags, unprivileged) = (self.init_flags, self.unprivileged);
+        let fd = wrap(self).map_err(|source| FanotifyError::Io {
+            source,
+            context: ErrorContext {
+                init_flags: Some(init_flags),
+                ..ErrorContext::default()
+            },
+        })?;
+        let mut fan = Fanotify::new(fd, init_flags);
+        fan.unprivileged = unprivileged;
+        Ok(fan)
+    }
+
+    pub(crate) fn read_events_once(&self) -> std::io::Result<Vec<Event>> {
         const BUFFER_SIZE: usize = 4096;
         let mut buffer = [0u8; BUFFER_SIZE];
-        let nread = self.read(&mut buffer)?;
-        Ok(Event::extract_from(&buffer[0..nread]))
+        let nread = (&*self).read(&mut buffer)?;
+        match Event::extract_all(&buffer[0..nread]) {
+            (events, None) => Ok(events),
+            (events, Some(error)) if events.is_empty() => Err(error.into()),
+            // dropping the events would leave their permission requests unanswered
+            (events, Some(error)) => {
+                log::warn!("malformed fanotify event: {error}");
+                Ok(events)
+            }
+        }
+    }
+
+    pub(crate) fn respond(&self, response: &Response) -> std::io::Result<usize> {
+        (&*self).write(response.as_bytes())
+    }
+
+    /// Checks whether pre-content events (`FAN_PRE_ACCESS`, Linux 6.14) can be watched on the
+    /// filesystem `path` lives on, by marking it on a throwaway `FAN_CLASS_PRE_CONTENT` group.
+    ///
+    /// Older kernels reject the mask bit and some filesystems don't support the hook, both are
+    /// reported as `Ok(false)`. Other errors, e.g. `EPERM` without `CAP_SYS_ADMIN`, are returned.
+    pub fn pre_content_supported<P: AsRef<Path>>(path: P) -> Result<bool, FanotifyError> {
+        let fan = Self::init(InitFlags::FAN_CLASS_PRE_CONTENT, EventFFlags::O_RDONLY)?;
+        match fan.mark(
+            MarkFlags::FAN_MARK_ADD,
+            MaskFlags::FAN_PRE_ACCESS,
+            None,
+            Some(path),
+        ) {
+            Ok(()) => Ok(true),
+            Err(FanotifyError::Unsupported { .. }) => Ok(false),
+            Err(error) if error.errno() == Some(Errno::new(libc::EOPNOTSUPP)) => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Reads into a caller-owned buffer and iterates the events in place, without allocating.
+    pub fn read_into<'a>(&mut self, buffer: &'a mut EventBuffer) -> std::io::Result<EventIter<'a>> {
+        if self.shutdown.is_some() && !self.wait_readable(None)? {
+            return Ok(buffer.events(0));
+        }
+        let nread = self.read(buffer.as_mut_slice())?;
+        Ok(buffer.events(nread))
     }
 
+    /// Writes a response to a permission event.
+    ///
+    /// Never waits: fanotify takes a response right away but never reports its fd as
+    /// writable, so event loops must not wait for writability before answering.
     pub fn write_response(&mut self, response: Response) -> std::io::Result<usize> {
-        self.write(unsafe {
-            std::slice::from_raw_parts(
-                (&response.inner as *const libc::fanotify_response).cast(),
-                size_of::<libc::fanotify_response>(),
-            )
-        })
+        self.respond(&response)
     }
 }
 
 impl<F> Fanotify<F> where F: AsRawFd {
-    pub fn mark<P: Into<String>>(
+    /// Adds, removes or flushes marks with raw flags, `operation` must contain exactly one of
+    /// `FAN_MARK_ADD`, `FAN_MARK_REMOVE` and `FAN_MARK_FLUSH`.
+    /// See [`Fanotify::add_mark`] and its siblings for a typed interface.
+    pub fn mark<P: AsRef<Path>>(
         &self,
         operation: MarkFlags,
         mask: MaskFlags,
         dirfd: Option<BorrowedFd>,
         path: Option<P>,
-    ) -> std::io::Result<()> {
+    ) -> Result<(), FanotifyError> {
+        self.mark_with(operation, mask, dirfd, path, true)
+    }
+
+    // marks the caller keeps track of itself, left out of the registry and the handle resolver
+    pub(crate) fn mark_unrecorded<P: AsRef<Path>>(
+        &self,
+        operation: MarkFlags,
+        mask: MaskFlags,
+        dirfd: Option<BorrowedFd>,
+        path: Option<P>,
+    ) -> Result<(), FanotifyError> {
+        self.mark_with(operation, mask, dirfd, path, false)
+    }
+
+    fn mark_with<P: AsRef<Path>>(
+        &self,
+        operation: MarkFlags,
+        mask: MaskFlags,
+        dirfd: Option<BorrowedFd>,
+        path: Option<P>,
+        record: bool,
+    ) -> Result<(), FanotifyError> {
+        let context_path = path.as_ref().map(|path| path.as_ref().to_owned());
+        let fid = self.init_flags.intersects(
+            InitFlags::FAN_REPORT_FID
+                | InitFlags::FAN_REPORT_DIR_FID
+                | InitFlags::FAN_REPORT_TARGET_FID,
+        );
+        let error = |error: std::io::Error| {
+            FanotifyError::from_mark(error, operation, mask, context_path.clone(), fid)
+        };
+
+        if self.unprivileged
+            && operation.intersects(MarkFlags::FAN_MARK_MOUNT | MarkFlags::FAN_MARK_FILESYSTEM)
+        {
+            return Err(FanotifyError::RequiresPrivilege {
+                what: "marking mounts, filesystems and mount namespaces",
+                context: ErrorContext::mark(operation, mask, context_path),
+            });
+        }
+
+        let operations = operation
+            & (MarkFlags::FAN_MARK_ADD | MarkFlags::FAN_MARK_REMOVE | MarkFlags::FAN_MARK_FLUSH);
+        if operations.iter().count() != 1 {
+            return Err(FanotifyError::invalid_argument(
+                "exactly one mark operation is required",
+                ErrorContext::mark(operation, mask, context_path),
+            ));
+        }
+
         let dirfd = match dirfd {
             Some(fd) => fd.as_raw_fd(),
             None => libc::AT_FDCWD,
         };
+        let registry_path = self
+            .registry
+            .as_ref()
+            .filter(|_| record)
+            .map(|_| registry_path(dirfd, path.as_ref().map(AsRef::as_ref)));
+        let path = match path {
+            Some(path) => Some(
+                CString::new(path.as_ref().as_os_str().as_bytes()).map_err(|e| error(e.into()))?,
+            ),
+            None => None,
+        };
+        // before marking: the resolver opens the object, which would raise an event on this
+        // group once it is marked, a permission event nobody answers while we are in here.
+        // Registering is best-effort, only resolving handles of this filesystem would fail
+        let registered = match self.resolver.as_ref().filter(|_| record) {
+            // a mount namespace fd is not on a filesystem that reports file handles
+            Some(resolver)
+                if operation.contains(MarkFlags::FAN_MARK_ADD)
+                    && !operation.contains(MarkFlags::FAN_MARK_MNTNS) =>
+            {
+                match resolver.register_at(dirfd, path.as_deref(), operation) {
+                    Ok((fsid, registered)) => registered.then_some((resolver, fsid)),
+                    Err(error) => {
+                        log::warn!(
+                            "can't register {context_path:?} with the handle resolver: {error}"
+                        );
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+
         let result = unsafe {
             // hold it here to prevent drop. don't merge two matches
-            if let Some(path) = path {
-                let path: String = path.into();
-                let cstr = CString::new(path)?;
+            if let Some(cstr) = &path {
                 libc::fanotify_mark(
                     self.fd.as_raw_fd(),
                     operation.bits(),
@@ -79,11 +365,42 @@ impl<F> Fanotify<F> where F: AsRawFd {
         };
 
         if result != 0 {
-            return Err(std::io::Error::last_os_error());
+            let source = std::io::Error::last_os_error();
+            if let Some((resolver, fsid)) = registered {
+                resolver.unregister(fsid);
+            }
+            return Err(error(source));
+        }
+
+        if let (Some(registry), Some(path)) = (&self.registry, registry_path) {
+            registry.lock().unwrap().record(operation, mask, path);
         }
 
         Ok(())
     }
+
+    /// Subscribes the filesystem `path` lives on to `FAN_FS_ERROR`.
+    /// The group must have been created with [`InitFlags::FS_ERROR_MONITOR`].
+    pub fn mark_fs_errors<P: AsRef<Path>>(&self, path: P) -> Result<(), FanotifyError> {
+        self.mark(
+            MarkFlags::FAN_MARK_ADD | MarkFlags::FAN_MARK_FILESYSTEM,
+            MaskFlags::FAN_FS_ERROR,
+            None,
+            Some(path),
+        )
+    }
+
+    /// Watches mounts being attached to and detached from the mount namespace of `nsfd`,
+    /// an fd of `/proc/<pid>/ns/mnt`. The group must be initialized with `FAN_REPORT_MNT`,
+    /// and the events carry the mount id, see [`Event::mnt_id`].
+    pub fn mark_mount_namespace<Fd: AsFd>(&self, nsfd: Fd) -> Result<(), FanotifyError> {
+        self.mark::<&Path>(
+            MarkFlags::FAN_MARK_ADD | MarkFlags::FAN_MARK_MNTNS,
+            MaskFlags::FAN_MNT_ATTACH | MaskFlags::FAN_MNT_DETACH,
+            Some(nsfd.as_fd()),
+            None,
+        )
+    }
 }
 
 impl<F: AsRawFd> AsRawFd for Fanotify<F> {
@@ -92,7 +409,30 @@ impl<F: AsRawFd> AsRawFd for Fanotify<F> {
     }
 }
 
+impl<F: AsFd> AsFd for Fanotify<F> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
 impl Read for Fanotify<OwnedFd> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Fanotify<OwnedFd> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// a read takes whole events and a write one whole response, so the fd can be shared
+impl Read for &Fanotify<OwnedFd> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         Ok(unsafe {
             let nread = libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len());
@@ -104,7 +444,7 @@ impl Read for Fanotify<OwnedFd> {
     }
 }
 
-impl Write for Fanotify<OwnedFd> {
+impl Write for &Fanotify<OwnedFd> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         Ok(unsafe {
             let nread = libc::write(self.fd.as_raw_fd(), buf.as_ptr().cast(), buf.len());
@@ -120,3 +460,34 @@ impl Write for Fanotify<OwnedFd> {
     }
 }
 
+// flags fanotify_init(2) refuses with EPERM without CAP_SYS_ADMIN
+pub(crate) fn check_unprivileged(
+    init_flags: InitFlags,
+    event_fd_flags: EventFFlags,
+) -> Result<(), FanotifyError> {
+    let privileged = [
+        (InitFlags::FAN_CLASS_CONTENT, "FAN_CLASS_CONTENT"),
+        (InitFlags::FAN_CLASS_PRE_CONTENT, "FAN_CLASS_PRE_CONTENT"),
+        (InitFlags::FAN_UNLIMITED_QUEUE, "FAN_UNLIMITED_QUEUE"),
+        (InitFlags::FAN_UNLIMITED_MARKS, "FAN_UNLIMITED_MARKS"),
+        (InitFlags::FAN_REPORT_TID, "FAN_REPORT_TID"),
+        (InitFlags::FAN_REPORT_PIDFD, "FAN_REPORT_PIDFD"),
+        // mount events need a mount namespace mark and exclude file handles
+        (InitFlags::FAN_REPORT_MNT, "FAN_REPORT_MNT"),
+    ];
+    let what = privileged
+        .iter()
+        .find(|(flag, _)| init_flags.contains(*flag))
+        .map(|(_, name)| *name)
+        .or_else(|| {
+            let fid = InitFlags::FAN_REPORT_FID | InitFlags::FAN_REPORT_DIR_FID;
+            (!init_flags.intersects(fid)).then_some("reporting events without file handles")
+        });
+    match what {
+        Some(what) => Err(FanotifyError::RequiresPrivilege {
+            what,
+            context: ErrorContext::init(init_flags, event_fd_flags),
+        }),
+        None => Ok(()),
+    }
+}
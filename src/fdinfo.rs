@@ -0,0 +1,231 @@
+use std::{
+    collections::HashMap,
+    os::fd::{AsFd, AsRawFd, RawFd},
+};
+
+use crate::{
+    consts::{EventFFlags, InitFlags, MarkFlags, MaskFlags},
+    fanotify::Fanotify,
+    messages::{FileHandle, Fsid},
+};
+
+/// Flags and marks of a fanotify group as the kernel reports them in `/proc/<pid>/fdinfo/<fd>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KernelState {
+    pub init_flags: InitFlags,
+    // includes flags the kernel adds on its own, like O_LARGEFILE
+    pub event_f_flags: EventFFlags,
+    pub marks: Vec<KernelMark>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KernelMark {
+    pub object: MarkObject,
+    // the flags kept with the mark: FAN_MARK_IGNORED_SURV_MODIFY, FAN_MARK_EVICTABLE and FAN_MARK_IGNORE
+    pub flags: MarkFlags,
+    pub mask: MaskFlags,
+    pub ignored_mask: MaskFlags,
+}
+
+/// Object a mark is attached to, identified the way fdinfo prints it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MarkObject {
+    Inode {
+        ino: u64,
+        // device number of the filesystem
+        sdev: u32,
+        handle_type: i32,
+        f_handle: Vec<u8>,
+    },
+    Mount {
+        mnt_id: u64,
+    },
+    Filesystem {
+        sdev: u32,
+    },
+    MountNamespace {
+        ns_id: u64,
+    },
+}
+
+impl MarkObject {
+    /// File handle of an inode mark. fdinfo doesn't print the fsid, so it has to be
+    /// known from elsewhere, e.g. [`HandleResolver::register`](crate::resolver::HandleResolver::register).
+    pub fn file_handle(&self, fsid: Fsid) -> Option<FileHandle> {
+        match self {
+            MarkObject::Inode {
+                handle_type,
+                f_handle,
+                ..
+            } => Some(FileHandle::new(fsid, *handle_type, f_handle.clone())),
+            _ => None,
+        }
+    }
+}
+
+impl KernelState {
+    /// Parses the content of an fdinfo file.
+    pub fn parse(fdinfo: &str) -> std::io::Result<Self> {
+        let mut flags = None;
+        let mut marks = Vec::new();
+
+        for line in fdinfo.lines() {
+            let Some(line) = line.strip_prefix("fanotify ") else {
+                continue;
+            };
+            let fields: HashMap<&str, &str> = line
+                .split_whitespace()
+                .filter_map(|field| field.split_once(':'))
+                .collect();
+            let hex = |key: &str| -> std::io::Result<u64> {
+                let value = fields
+                    .get(key)
+                    .ok_or_else(|| invalid_data(format!("missing {key} in {line:?}")))?;
+                u64::from_str_radix(value, 16)
+                    .map_err(|_| invalid_data(format!("bad {key} in {line:?}")))
+            };
+
+            if fields.contains_key("event-flags") {
+                flags = Some((
+                    InitFlags::from_bits_retain(hex("flags")? as u32),
+                    EventFFlags::from_bits_retain(hex("event-flags")? as u32),
+                ));
+                continue;
+            }
+
+            let object = if fields.contains_key("ino") {
+                let f_handle = fields.get("f_handle").copied().unwrap_or_default();
+                MarkObject::Inode {
+                    ino: hex("ino")?,
+                    sdev: hex("sdev")? as u32,
+                    handle_type: hex("fhandle-type").unwrap_or_default() as i32,
+                    f_handle: parse_hex_bytes(f_handle)
+                        .ok_or_else(|| invalid_data(format!("bad f_handle in {line:?}")))?,
+                }
+            } else if fields.contains_key("mnt_id") {
+                MarkObject::Mount {
+                    mnt_id: hex("mnt_id")?,
+                }
+            } else if let Some(ns_id) = fields.get("mnt_ns") {
+                // printed in decimal, unlike everything else
+                MarkObject::MountNamespace {
+                    ns_id: ns_id
+                        .parse()
+                        .map_err(|_| invalid_data(format!("bad mnt_ns in {line:?}")))?,
+                }
+            } else if fields.contains_key("sdev") {
+                MarkObject::Filesystem {
+                    sdev: hex("sdev")? as u32,
+                }
+            } else {
+                // a kind of mark added by a newer kernel
+                continue;
+            };
+            marks.push(KernelMark {
+                object,
+                flags: MarkFlags::from_bits_retain(hex("mflags")? as u32),
+                mask: MaskFlags::from_bits_retain(hex("mask")?),
+                ignored_mask: MaskFlags::from_bits_retain(hex("ignored_mask")?),
+            });
+        }
+
+        let Some((init_flags, event_f_flags)) = flags else {
+            return Err(invalid_data("not a fanotify fd".to_owned()));
+        };
+        Ok(Self {
+            init_flags,
+            event_f_flags,
+            marks,
+        })
+    }
+}
+
+/// Reads the state of a fanotify group held by this process.
+pub fn kernel_state<Fd: AsFd>(fd: Fd) -> std::io::Result<KernelState> {
+    read_fdinfo(fd.as_fd().as_raw_fd())
+}
+
+/// Reads the state of a fanotify group held by another process, which needs
+/// the same permissions as reading its `/proc/<pid>/fdinfo`.
+pub fn kernel_state_of(pid: i32, fd: RawFd) -> std::io::Result<KernelState> {
+    let fdinfo = std::fs::read_to_string(format!("/proc/{pid}/fdinfo/{fd}"))?;
+    KernelState::parse(&fdinfo)
+}
+
+impl<F> Fanotify<F>
+where
+    F: AsRawFd,
+{
+    /// What the kernel thinks is marked on this group, see [`KernelState`].
+    pub fn kernel_state(&self) -> std::io::Result<KernelState> {
+        read_fdinfo(self.as_raw_fd())
+    }
+}
+
+fn read_fdinfo(fd: RawFd) -> std::io::Result<KernelState> {
+    let fdinfo = std::fs::read_to_string(format!("/proc/self/fdinfo/{fd}"))?;
+    KernelState::parse(&fdinfo)
+}
+
+fn parse_hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{KernelState, MarkObject};
+    use crate::consts::{InitFlags, MarkFlags, MaskFlags};
+
+    const FDINFO: &str = "pos:\t0
+flags:\t02
+mnt_id:\t17
+ino:\t26
+fanotify flags:10 event-flags:8000
+fanotify mnt_id:1c mflags:0 mask:20 ignored_mask:0
+fanotify ino:12a00c sdev:fe00000 mflags:200 mask:2 ignored_mask:8 fhandle-bytes:8 fhandle-type:1 f_handle:0ca0120017d0e370
+fanotify sdev:fe00001 mflags:0 mask:8000 ignored_mask:0
+";
+
+    #[test]
+    fn test_parse_fdinfo() {
+        let state = KernelState::parse(FDINFO).unwrap();
+        assert_eq!(state.init_flags, InitFlags::FAN_UNLIMITED_QUEUE);
+        // O_LARGEFILE as the kernel sees it, libc defines it as 0 on 64 bit targets
+        assert_eq!(state.event_f_flags.bits(), 0x8000);
+        assert_eq!(state.marks.len(), 3);
+
+        assert_eq!(state.marks[0].object, MarkObject::Mount { mnt_id: 0x1c });
+        assert_eq!(state.marks[0].mask, MaskFlags::FAN_OPEN);
+
+        let inode = &state.marks[1];
+        assert_eq!(
+            inode.object,
+            MarkObject::Inode {
+                ino: 0x12a00c,
+                sdev: 0xfe00000,
+                handle_type: 1,
+                f_handle: vec![0x0c, 0xa0, 0x12, 0x00, 0x17, 0xd0, 0xe3, 0x70],
+            }
+        );
+        assert_eq!(inode.flags, MarkFlags::FAN_MARK_EVICTABLE);
+        assert_eq!(inode.ignored_mask, MaskFlags::FAN_CLOSE_WRITE);
+
+        assert_eq!(
+            state.marks[2].object,
+            MarkObject::Filesystem { sdev: 0xfe00001 }
+        );
+        assert_eq!(state.marks[2].mask, MaskFlags::FAN_FS_ERROR);
+
+        assert!(KernelState::parse("pos:\t0\nflags:\t02\n").is_err());
+    }
+}
@@ -1,13 +1,32 @@
 #[macro_use]
 mod macros;
 
+pub mod builder;
+pub mod cache;
+pub mod capabilities;
 pub mod consts;
+pub mod dispatcher;
 pub mod error;
 pub mod fanotify;
+pub mod fdinfo;
+pub mod mark;
 pub mod messages;
+pub mod permission;
+pub mod policy;
 pub mod prelude;
+pub mod resolver;
+pub mod shutdown;
 
 pub use bitflags;
 
 #[cfg(feature="aio")]
-pub mod aio;
\ No newline at end of file
+pub mod aio;
+
+#[cfg(feature="async-io")]
+pub mod async_io;
+
+#[cfg(feature="mio")]
+pub mod mio;
+
+#[cfg(feature="exec-guard")]
+pub mod exec_guard;
\ No newline at end of file
@@ -2,6 +2,9 @@
     Copied from nix crate and modified to allow multiple patterns in a single block.
 
     This simplifies flag groups definition.
+
+    Values are looked up in crate::consts instead of libc, so that flags of newer kernels
+    which libc doesn't define yet can be declared there.
 */
 macro_rules! fa_bitflags {
     // modified: accept a list of pub struct, force cast to T
@@ -26,7 +29,7 @@ macro_rules! fa_bitflags {
                 $(
                     $(#[$inner $($args)*])*
                     // always cast to $T
-                    const $Flag = libc::$Flag as $T;
+                    const $Flag = $crate::consts::$Flag as $T;
                 )+
             }
         }
@@ -59,7 +62,7 @@ macro_rules! fa_bitflags {
             pub struct $BitFlags: $T {
                 $(
                     $(#[$inner $($args)*])*
-                    const $Flag = libc::$Flag $(as $cast)*;
+                    const $Flag = $crate::consts::$Flag $(as $cast)*;
                 )+
             }
         }
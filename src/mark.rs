@@ -0,0 +1,465 @@
+use std::{
+    collections::BTreeMap,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    consts::{MarkFlags, MaskFlags},
+    error::{Errno, ErrorContext, FanotifyError},
+    fanotify::Fanotify,
+};
+
+/// What a mark is attached to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum MarkTarget {
+    // the file or directory itself
+    Inode,
+    // the mount the path is on
+    Mount,
+    // the whole filesystem the path is on, Linux 4.20
+    Filesystem,
+    // the mount namespace of a `/proc/<pid>/ns/mnt` file, Linux 6.15
+    MountNamespace,
+}
+
+impl MarkTarget {
+    pub fn flags(&self) -> MarkFlags {
+        match self {
+            MarkTarget::Inode => MarkFlags::empty(),
+            MarkTarget::Mount => MarkFlags::FAN_MARK_MOUNT,
+            MarkTarget::Filesystem => MarkFlags::FAN_MARK_FILESYSTEM,
+            MarkTarget::MountNamespace => MarkFlags::FAN_MARK_MNTNS,
+        }
+    }
+
+    pub fn from_flags(flags: MarkFlags) -> Self {
+        // FAN_MARK_MNTNS shares its bits with FAN_MARK_MOUNT and FAN_MARK_FILESYSTEM
+        if flags.contains(MarkFlags::FAN_MARK_MNTNS) {
+            MarkTarget::MountNamespace
+        } else if flags.contains(MarkFlags::FAN_MARK_FILESYSTEM) {
+            MarkTarget::Filesystem
+        } else if flags.contains(MarkFlags::FAN_MARK_MOUNT) {
+            MarkTarget::Mount
+        } else {
+            MarkTarget::Inode
+        }
+    }
+}
+
+/// Operation of a `fanotify_mark(2)` call, exactly one per call.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MarkOp {
+    Add,
+    Remove,
+    // remove all marks of a target kind
+    Flush,
+}
+
+impl MarkOp {
+    pub fn flags(&self) -> MarkFlags {
+        match self {
+            MarkOp::Add => MarkFlags::FAN_MARK_ADD,
+            MarkOp::Remove => MarkFlags::FAN_MARK_REMOVE,
+            MarkOp::Flush => MarkFlags::FAN_MARK_FLUSH,
+        }
+    }
+}
+
+/// A mark as recorded by the mark registry, see [`Fanotify::with_mark_registry`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MarkSpec {
+    pub target: MarkTarget,
+    // as passed to mark, made absolute if it was relative to a dirfd
+    pub path: PathBuf,
+    pub mask: MaskFlags,
+    pub ignore_mask: MaskFlags,
+    // additional flags, e.g. FAN_MARK_DONT_FOLLOW, without operation, target and ignore flags
+    pub flags: MarkFlags,
+}
+
+impl MarkSpec {
+    pub fn new<P: Into<PathBuf>>(target: MarkTarget, path: P, mask: MaskFlags) -> Self {
+        Self {
+            target,
+            path: path.into(),
+            mask,
+            ignore_mask: MaskFlags::empty(),
+            flags: MarkFlags::empty(),
+        }
+    }
+
+    pub fn with_ignore_mask(mut self, ignore_mask: MaskFlags) -> Self {
+        self.ignore_mask = ignore_mask;
+        self
+    }
+
+    pub fn with_flags(mut self, flags: MarkFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    fn key(&self) -> (MarkTarget, PathBuf) {
+        (self.target, self.path.clone())
+    }
+}
+
+// ignore flags select the mask a call applies to, they are not a property of the mark
+const IGNORE_FLAGS: MarkFlags = MarkFlags::FAN_MARK_IGNORED_MASK.union(MarkFlags::FAN_MARK_IGNORE);
+
+// marks of a group as seen by the calls to Fanotify::mark that succeeded
+#[derive(Default)]
+pub(crate) struct MarkRegistry {
+    marks: BTreeMap<(MarkTarget, PathBuf), MarkSpec>,
+}
+
+impl MarkRegistry {
+    pub(crate) fn record(&mut self, operation: MarkFlags, mask: MaskFlags, path: PathBuf) {
+        let target = MarkTarget::from_flags(operation);
+        if operation.contains(MarkFlags::FAN_MARK_FLUSH) {
+            self.marks.retain(|(kind, _), _| *kind != target);
+            return;
+        }
+
+        let ignore = operation.intersects(IGNORE_FLAGS);
+        let key = (target, path);
+        if operation.contains(MarkFlags::FAN_MARK_ADD) {
+            let flags = operation - RESERVED_FLAGS - IGNORE_FLAGS;
+            let spec = self
+                .marks
+                .entry(key.clone())
+                .or_insert_with(|| MarkSpec::new(key.0, key.1, MaskFlags::empty()));
+            if ignore {
+                spec.ignore_mask |= mask;
+            } else {
+                spec.mask |= mask;
+            }
+            spec.flags |= flags;
+        } else if let Some(spec) = self.marks.get_mut(&key) {
+            if ignore {
+                spec.ignore_mask -= mask;
+            } else {
+                spec.mask -= mask;
+            }
+            // the kernel drops a mark once both masks are empty
+            if spec.mask.is_empty() && spec.ignore_mask.is_empty() {
+                self.marks.remove(&key);
+            }
+        }
+    }
+}
+
+// key of a mark in the registry, the path relative to dirfd is made absolute
+pub(crate) fn registry_path(dirfd: RawFd, path: Option<&Path>) -> PathBuf {
+    match path {
+        Some(path) if path.is_absolute() || dirfd == libc::AT_FDCWD => path.to_owned(),
+        _ => {
+            let dir = std::fs::read_link(format!("/proc/self/fd/{dirfd}")).unwrap_or_default();
+            match path {
+                Some(path) => dir.join(path),
+                None => dir,
+            }
+        }
+    }
+}
+
+// flags that are chosen by MarkOp and MarkTarget, not by the caller
+const RESERVED_FLAGS: MarkFlags = MarkFlags::FAN_MARK_ADD
+    .union(MarkFlags::FAN_MARK_REMOVE)
+    .union(MarkFlags::FAN_MARK_FLUSH)
+    .union(MarkFlags::FAN_MARK_MNTNS);
+
+impl<F> Fanotify<F>
+where
+    F: AsRawFd,
+{
+    /// Typed form of [`Fanotify::mark`]. `flags` are additional flags like
+    /// `FAN_MARK_DONT_FOLLOW` or `FAN_MARK_EVICTABLE`, flags that choose an operation
+    /// or a target are rejected.
+    pub fn mark_target<P: AsRef<Path>>(
+        &self,
+        op: MarkOp,
+        target: MarkTarget,
+        flags: MarkFlags,
+        mask: MaskFlags,
+        dirfd: Option<BorrowedFd>,
+        path: Option<P>,
+    ) -> Result<(), FanotifyError> {
+        if flags.intersects(RESERVED_FLAGS) {
+            return Err(FanotifyError::invalid_argument(
+                "operation and target flags are chosen by MarkOp and MarkTarget",
+                ErrorContext::mark(flags, mask, path.map(|path| path.as_ref().to_owned())),
+            ));
+        }
+        self.mark(op.flags() | target.flags() | flags, mask, dirfd, path)
+    }
+
+    pub fn add_mark<P: AsRef<Path>>(
+        &self,
+        target: MarkTarget,
+        mask: MaskFlags,
+        path: P,
+    ) -> Result<(), FanotifyError> {
+        self.mark_target(
+            MarkOp::Add,
+            target,
+            MarkFlags::empty(),
+            mask,
+            None,
+            Some(path),
+        )
+    }
+
+    /// Like [`Fanotify::add_mark`], with `path` relative to `dirfd`.
+    pub fn add_mark_at<Fd: AsFd, P: AsRef<Path>>(
+        &self,
+        target: MarkTarget,
+        mask: MaskFlags,
+        dirfd: Fd,
+        path: P,
+    ) -> Result<(), FanotifyError> {
+        self.mark_target(
+            MarkOp::Add,
+            target,
+            MarkFlags::empty(),
+            mask,
+            Some(dirfd.as_fd()),
+            Some(path),
+        )
+    }
+
+    pub fn remove_mark<P: AsRef<Path>>(
+        &self,
+        target: MarkTarget,
+        mask: MaskFlags,
+        path: P,
+    ) -> Result<(), FanotifyError> {
+        self.mark_target(
+            MarkOp::Remove,
+            target,
+            MarkFlags::empty(),
+            mask,
+            None,
+            Some(path),
+        )
+    }
+
+    /// Adds `mask` to the ignored mask of the target, events in it are not reported
+    /// even when another mark asks for them.
+    pub fn add_ignore<P: AsRef<Path>>(
+        &self,
+        target: MarkTarget,
+        mask: MaskFlags,
+        path: P,
+    ) -> Result<(), FanotifyError> {
+        self.mark_target(
+            MarkOp::Add,
+            target,
+            MarkFlags::FAN_MARK_IGNORED_MASK,
+            mask,
+            None,
+            Some(path),
+        )
+    }
+
+    /// Removes every mark of the `target` kind from the group.
+    pub fn flush(&self, target: MarkTarget) -> Result<(), FanotifyError> {
+        self.mark_target::<&Path>(
+            MarkOp::Flush,
+            target,
+            MarkFlags::empty(),
+            MaskFlags::empty(),
+            None,
+            None,
+        )
+    }
+
+    /// Marks recorded since [`Fanotify::with_mark_registry`], `None` without a registry.
+    pub fn marks(&self) -> Option<Vec<MarkSpec>> {
+        let registry = self.registry.as_ref()?.lock().unwrap();
+        Some(registry.marks.values().cloned().collect())
+    }
+
+    /// Adds `mask` to the mark unless the registry already has all of it.
+    pub fn ensure_mark<P: AsRef<Path>>(
+        &self,
+        target: MarkTarget,
+        mask: MaskFlags,
+        path: P,
+    ) -> Result<(), FanotifyError> {
+        let path = path.as_ref();
+        if let Some(registry) = &self.registry {
+            let registry = registry.lock().unwrap();
+            if let Some(spec) = registry.marks.get(&(target, path.to_owned())) {
+                if spec.mask.contains(mask) {
+                    return Ok(());
+                }
+            }
+        }
+        self.add_mark(target, mask, path)
+    }
+
+    /// Removes `mask` from the mark, succeeding if there is no such mark.
+    pub fn ensure_unmarked<P: AsRef<Path>>(
+        &self,
+        target: MarkTarget,
+        mask: MaskFlags,
+        path: P,
+    ) -> Result<(), FanotifyError> {
+        let spec = MarkSpec::new(target, path.as_ref(), mask);
+        self.unapply(&spec)?;
+        // a mark the kernel didn't know about is dropped from the registry as well
+        if let Some(registry) = &self.registry {
+            registry.lock().unwrap().record(
+                MarkFlags::FAN_MARK_REMOVE | target.flags(),
+                mask,
+                spec.path,
+            );
+        }
+        Ok(())
+    }
+
+    /// Brings the marks of the group to `desired`, removing recorded marks that are not
+    /// in it and adding or updating the others. Requires a mark registry.
+    ///
+    /// Stops at the first error, the registry reflects the calls that succeeded until then.
+    pub fn diff_and_apply<I: IntoIterator<Item = MarkSpec>>(
+        &self,
+        desired: I,
+    ) -> Result<(), FanotifyError> {
+        let Some(current) = self.marks() else {
+            return Err(FanotifyError::invalid_argument(
+                "mark registry is not enabled",
+                ErrorContext::default(),
+            ));
+        };
+        let mut current: BTreeMap<_, _> =
+            current.into_iter().map(|spec| (spec.key(), spec)).collect();
+
+        for spec in desired {
+            match current.remove(&spec.key()) {
+                None => self.apply(&spec)?,
+                Some(old) if old.flags != spec.flags => {
+                    self.unapply(&old)?;
+                    self.apply(&spec)?;
+                }
+                Some(old) => {
+                    let removed = MarkSpec {
+                        mask: old.mask - spec.mask,
+                        ignore_mask: old.ignore_mask - spec.ignore_mask,
+                        ..old.clone()
+                    };
+                    let added = MarkSpec {
+                        mask: spec.mask - old.mask,
+                        ignore_mask: spec.ignore_mask - old.ignore_mask,
+                        ..spec
+                    };
+                    // add first, so the kernel doesn't drop a mark that is only changing its mask
+                    self.apply(&added)?;
+                    self.unapply(&removed)?;
+                }
+            }
+        }
+        for old in current.values() {
+            self.unapply(old)?;
+        }
+        Ok(())
+    }
+
+    fn apply(&self, spec: &MarkSpec) -> Result<(), FanotifyError> {
+        let flags = spec.flags - RESERVED_FLAGS - IGNORE_FLAGS;
+        if !spec.mask.is_empty() {
+            self.mark_target(
+                MarkOp::Add,
+                spec.target,
+                flags,
+                spec.mask,
+                None,
+                Some(&spec.path),
+            )?;
+        }
+        if !spec.ignore_mask.is_empty() {
+            self.mark_target(
+                MarkOp::Add,
+                spec.target,
+                flags | MarkFlags::FAN_MARK_IGNORED_MASK,
+                spec.ignore_mask,
+                None,
+                Some(&spec.path),
+            )?;
+        }
+        Ok(())
+    }
+
+    // removing from a mark that doesn't exist (anymore) is not an error here
+    fn unapply(&self, spec: &MarkSpec) -> Result<(), FanotifyError> {
+        let ignore_enoent = |result: Result<(), FanotifyError>| match result {
+            Err(error) if error.errno() == Some(Errno::new(libc::ENOENT)) => Ok(()),
+            result => result,
+        };
+        if !spec.ignore_mask.is_empty() {
+            ignore_enoent(self.mark_target(
+                MarkOp::Remove,
+                spec.target,
+                MarkFlags::FAN_MARK_IGNORED_MASK,
+                spec.ignore_mask,
+                None,
+                Some(&spec.path),
+            ))?;
+        }
+        if !spec.mask.is_empty() {
+            ignore_enoent(self.mark_target(
+                MarkOp::Remove,
+                spec.target,
+                MarkFlags::empty(),
+                spec.mask,
+                None,
+                Some(&spec.path),
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::{MarkRegistry, MarkTarget};
+    use crate::consts::{MarkFlags, MaskFlags};
+
+    #[test]
+    fn test_registry_record() {
+        let mut registry = MarkRegistry::default();
+        let path = PathBuf::from("/tmp");
+        let add = MarkFlags::FAN_MARK_ADD | MarkFlags::FAN_MARK_MOUNT;
+        registry.record(add, MaskFlags::FAN_OPEN, path.clone());
+        registry.record(
+            add | MarkFlags::FAN_MARK_IGNORED_MASK,
+            MaskFlags::FAN_CLOSE,
+            path.clone(),
+        );
+        registry.record(MarkFlags::FAN_MARK_ADD, MaskFlags::FAN_MODIFY, path.clone());
+
+        let spec = &registry.marks[&(MarkTarget::Mount, path.clone())];
+        assert_eq!(spec.mask, MaskFlags::FAN_OPEN);
+        assert_eq!(spec.ignore_mask, MaskFlags::FAN_CLOSE);
+        assert_eq!(registry.marks.len(), 2);
+
+        let remove = MarkFlags::FAN_MARK_REMOVE | MarkFlags::FAN_MARK_MOUNT;
+        registry.record(remove, MaskFlags::FAN_OPEN, path.clone());
+        registry.record(
+            remove | MarkFlags::FAN_MARK_IGNORED_MASK,
+            MaskFlags::FAN_CLOSE,
+            path.clone(),
+        );
+        assert_eq!(registry.marks.len(), 1);
+
+        registry.record(
+            MarkFlags::FAN_MARK_FLUSH,
+            MaskFlags::empty(),
+            PathBuf::new(),
+        );
+        assert!(registry.marks.is_empty());
+    }
+}
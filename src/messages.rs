@@ -1,7 +1,15 @@
-use std::{mem::MaybeUninit, os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd}};
-
-use crate::consts::MaskFlags;
+use std::{
+    ffi::{OsStr, OsString},
+    os::{
+        fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+        unix::ffi::OsStrExt,
+    },
+};
 
+use crate::{
+    consts::{fan_deny_errno, MaskFlags, FAN_ERRNO_MASK, FAN_ERRNO_SHIFT},
+    error::{Errno, ParseError},
+};
 
 pub struct Event {
     pub fanotify_event_metadata: libc::fanotify_event_metadata,
@@ -19,94 +27,65 @@ impl Event {
         }
     }
 
-    pub fn extract_from(buf: &[u8]) -> Vec<Self> {
-        const EVENT_SIZE: usize = size_of::<libc::fanotify_event_metadata>();
-        let nread = buf.len() as usize;
+    /// Parses every event record contained in `buf`, usually the bytes returned by a single
+    /// `read(2)` on the fanotify fd.
+    ///
+    /// Lengths reported by the kernel are checked against the buffer before anything is copied.
+    /// Info records of a type this crate does not know are kept as [`EventInfo::Unknown`].
+    /// On error, every event is dropped, closing their fds, see [`Event::extract_all`] to keep
+    /// them.
+    pub fn extract_from(buf: &[u8]) -> Result<Vec<Self>, ParseError> {
+        match Self::extract_all(buf) {
+            (events, None) => Ok(events),
+            (_, Some(error)) => Err(error),
+        }
+    }
 
+    /// Parses like [`Event::extract_from`], but keeps the events around a malformed record, so
+    /// their fds stay open and permission events among them can still be answered.
+    ///
+    /// An event whose info records are malformed is kept with the ones before the bad record.
+    /// The records after a malformed header can't be found, their fds are left untouched.
+    /// Returns the first error along with the events.
+    pub fn extract_all(buf: &[u8]) -> (Vec<Self>, Option<ParseError>) {
         let mut result = Vec::new();
+        let mut error = None;
         let mut offset = 0;
-        unsafe {
-            // #define FAN_EVENT_OK(meta, len) \
-            //   ((long)(len) => (long)FAN_EVENT_METADATA_LEN)                && // rest buffer can contain a metadata struct
-            //   (long)(meta) ->event_len >= (long)FAN_FAN_EVENT_METADATA_LEN && // struct contains valid size (not implemented)
-            //   (long)(meta) ->event_len <= (long)(len)                         // struct does not read over buffer boundary (not implemented)
-            while offset + EVENT_SIZE <= nread {
-                let mut uninited: MaybeUninit<libc::fanotify_event_metadata> =
-                    MaybeUninit::uninit();
-                std::ptr::copy(
-                    buf.as_ptr().add(offset),
-                    uninited.as_mut_ptr().cast(),
-                    EVENT_SIZE,
-                );
-                let event = uninited.assume_init();
-
-                #[repr(C)]
-                union fanotify_event_info {
-                    header: libc::fanotify_event_info_header,
-                    fid: libc::fanotify_event_info_fid,
-                    pidfd: libc::fanotify_event_info_pidfd,
-                    error: libc::fanotify_event_info_error,
+        while offset < buf.len() {
+            let metadata = match read_metadata(buf, offset) {
+                Ok(metadata) => metadata,
+                Err(header_error) => {
+                    error.get_or_insert(header_error);
+                    break;
                 }
+            };
+            let event_len = metadata.event_len as usize;
 
-                let mut event_info = Vec::new();
-
-                const HEADER_SIZE: usize = std::mem::size_of::<libc::fanotify_event_info_header>();
-
-                let mut header_offset = EVENT_SIZE;
-                while header_offset + HEADER_SIZE < event.event_len as usize {
-                    let mut uninited_event_info: MaybeUninit<fanotify_event_info> =
-                        MaybeUninit::uninit();
-                    // 1. copy header
-                    std::ptr::copy(
-                        buf.as_ptr().add(offset + EVENT_SIZE),
-                        uninited_event_info.as_mut_ptr().cast(),
-                        EVENT_SIZE,
-                    );
-                    // 2. copy inner data
-                    std::ptr::copy(
-                        buf.as_ptr().add(offset + header_offset),
-                        uninited_event_info.as_mut_ptr().add(header_offset).cast(),
-                        (*uninited_event_info.as_ptr()).header.len as usize - HEADER_SIZE,
-                    );
-                    let event_info_len = (*uninited_event_info.as_ptr()).header.len as usize;
-                    let event_info_type = (*uninited_event_info.as_ptr()).header.info_type;
-                    // 3. save inner data according to info_type value
-                    match event_info_type {
-                        libc::FAN_EVENT_INFO_TYPE_FID
-                        | libc::FAN_EVENT_INFO_TYPE_DFID_NAME
-                        | libc::FAN_EVENT_INFO_TYPE_DFID => {
-                            event_info.push(EventInfo::Fid(uninited_event_info.assume_init().fid));
-                        }
-                        libc::FAN_EVENT_INFO_TYPE_PIDFD => {
-                            event_info
-                                .push(EventInfo::PidFd(uninited_event_info.assume_init().pidfd));
-                        }
-                        libc::FAN_EVENT_INFO_TYPE_ERROR => {
-                            event_info
-                                .push(EventInfo::PidFd(uninited_event_info.assume_init().pidfd));
-                        }
-                        _ => {
-                            panic!("unknown fan_event_info_header.type={event_info_type}")
-                        }
+            // own the fd from now on
+            let mut event = Event::new(metadata, Vec::new());
+            for event_info in InfoRecords::new(&buf[..offset + event_len], offset, &metadata) {
+                match event_info {
+                    Ok(event_info) => event.event_info.push(event_info),
+                    Err(info_error) => {
+                        error.get_or_insert(info_error);
                     }
-                    header_offset += event_info_len;
                 }
-
-                // #define FAN_EVENT_NEXT(meta, len) ((len) -= (meta)->event_len, (struct fanotify_event_metadata*)(((char*)(meta)) + (meta) -> event_len)
-                // meta = FAN_EVENT_NEXT(meta, len) translate to:
-                //   len -= meta->event_len; // shrink rest length
-                //   ,                       // comma operator, evaluate first express, but not using its result
-                //
-                //   meta = (struct fanotify_event_metadata*) (  // cast pointer back to metadata type
-                //      ((char*)(meta))                          // discard metadata type to increase pointer by 1
-                //      + (meta) -> event_len                    // add event_len to move to next
-                //   );
-                offset += event.event_len as usize;
-                result.push(Event::new(event, event_info));
             }
+
+            // #define FAN_EVENT_NEXT(meta, len) ((len) -= (meta)->event_len, (struct fanotify_event_metadata*)(((char*)(meta)) + (meta) -> event_len)
+            // meta = FAN_EVENT_NEXT(meta, len) translate to:
+            //   len -= meta->event_len; // shrink rest length
+            //   ,                       // comma operator, evaluate first express, but not using its result
+            //
+            //   meta = (struct fanotify_event_metadata*) (  // cast pointer back to metadata type
+            //      ((char*)(meta))                          // discard metadata type to increase pointer by 1
+            //      + (meta) -> event_len                    // add event_len to move to next
+            //   );
+            offset += event_len;
+            result.push(event);
         }
 
-        result
+        (result, error)
     }
 
     // compatible to nix::sys::fanotify::FanotifyEvent
@@ -116,7 +95,7 @@ impl Event {
     pub fn check_metadata_version(&self) -> bool {
         self.fanotify_event_metadata.vers == libc::FANOTIFY_METADATA_VERSION
     }
-    pub fn fd(&self) -> Option<BorrowedFd> {
+    pub fn fd(&self) -> Option<BorrowedFd<'_>> {
         if self.fanotify_event_metadata.fd == libc::FAN_NOFD {
             None
         } else {
@@ -138,10 +117,70 @@ impl Event {
 
         unsafe { OwnedFd::from_raw_fd(fd) }
     }
+
+    /// Pidfd of the process that caused the event, with `FAN_REPORT_PIDFD`.
+    /// It is owned by the event and closed together with it.
+    pub fn pidfd(&self) -> Option<BorrowedFd<'_>> {
+        match self.pidfd_info()? {
+            PidFdInfo::Fd(fd) => Some(unsafe { BorrowedFd::borrow_raw(fd) }),
+            _ => None,
+        }
+    }
+    pub fn pidfd_info(&self) -> Option<PidFdInfo> {
+        self.event_info
+            .iter()
+            .find_map(|event_info| match event_info {
+                EventInfo::PidFd(pidfd) => Some(*pidfd),
+                _ => None,
+            })
+    }
+
+    pub fn fs_error(&self) -> Option<&FsError> {
+        self.event_info
+            .iter()
+            .find_map(|event_info| match event_info {
+                EventInfo::Error(error) => Some(error),
+                _ => None,
+            })
+    }
+
+    /// Byte range of a pre-content event as `(offset, count)`.
+    /// Events without a range record, e.g. on open, cover the whole file.
+    pub fn range(&self) -> Option<(u64, u64)> {
+        self.event_info
+            .iter()
+            .find_map(|event_info| match event_info {
+                EventInfo::Range { offset, count } => Some((*offset, *count)),
+                _ => None,
+            })
+    }
+
+    /// Mount attached or detached by a mount namespace event.
+    pub fn mnt_id(&self) -> Option<u64> {
+        self.event_info
+            .iter()
+            .find_map(|event_info| match event_info {
+                EventInfo::Mount { mnt_id } => Some(*mnt_id),
+                _ => None,
+            })
+    }
+
+    // like forget_fd, the record reads FAN_NOPIDFD afterwards
+    pub fn take_pidfd(&mut self) -> Option<OwnedFd> {
+        for event_info in self.event_info.iter_mut() {
+            if let EventInfo::PidFd(PidFdInfo::Fd(fd)) = *event_info {
+                *event_info = EventInfo::PidFd(PidFdInfo::NoPidFd);
+                return Some(unsafe { OwnedFd::from_raw_fd(fd) });
+            }
+        }
+        None
+    }
 }
 
 impl Drop for Event {
     fn drop(&mut self) {
+        drop(self.take_pidfd());
+
         if self.fanotify_event_metadata.fd == libc::FAN_NOFD {
             return;
         }
@@ -153,17 +192,540 @@ impl Drop for Event {
     }
 }
 
+/// Caller-owned buffer that events are read into by `read_into`, reused across reads.
+pub struct EventBuffer {
+    buf: Vec<u8>,
+}
+
+impl EventBuffer {
+    pub const DEFAULT_CAPACITY: usize = 64 * 1024;
+
+    pub fn new() -> Self {
+        Self::with_capacity(Self::DEFAULT_CAPACITY)
+    }
+
+    // fanotify refuses reads into a buffer too small to hold the next event (EINVAL),
+    // so keep room for a few events with info records
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            buf: vec![0; capacity],
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+
+    // view the first `nread` bytes as event records
+    pub(crate) fn events(&mut self, nread: usize) -> EventIter<'_> {
+        EventIter {
+            buf: &mut self.buf[..nread],
+            offset: 0,
+        }
+    }
+}
+
+impl Default for EventBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Lending iterator over the events of one read, borrowing the [`EventBuffer`].
+///
+/// Every yielded [`EventRef`] closes its fd when dropped, just like [`Event`] does.
+/// Fds of events that were never yielded are closed when the iterator is dropped.
+pub struct EventIter<'a> {
+    buf: &'a mut [u8],
+    offset: usize,
+}
+
+impl EventIter<'_> {
+    // not an Iterator: each EventRef borrows the iterator until it is dropped
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<Result<EventRef<'_>, ParseError>> {
+        if self.offset >= self.buf.len() {
+            return None;
+        }
+        let offset = self.offset;
+        match read_metadata(self.buf, offset) {
+            Ok(metadata) => {
+                let event_len = metadata.event_len as usize;
+                self.offset += event_len;
+                Some(Ok(EventRef {
+                    buf: &mut self.buf[..offset + event_len],
+                    offset,
+                }))
+            }
+            Err(error) => {
+                // the rest of the buffer can't be trusted any more
+                self.offset = self.buf.len();
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+impl Drop for EventIter<'_> {
+    fn drop(&mut self) {
+        while let Some(Ok(_event)) = self.next() {}
+    }
+}
+
+/// Borrowed view of one event record inside an [`EventBuffer`].
+pub struct EventRef<'a> {
+    // buffer up to the end of this record, the record itself starts at `offset`
+    buf: &'a mut [u8],
+    offset: usize,
+}
+
+impl EventRef<'_> {
+    pub fn metadata(&self) -> libc::fanotify_event_metadata {
+        // validated when the iterator yielded this record
+        read_metadata(self.buf, self.offset).unwrap()
+    }
+    pub fn metadata_version(&self) -> u8 {
+        self.metadata().vers
+    }
+    pub fn fd(&self) -> Option<BorrowedFd<'_>> {
+        let fd = self.metadata().fd;
+        if fd == libc::FAN_NOFD {
+            None
+        } else {
+            Some(unsafe { BorrowedFd::borrow_raw(fd) })
+        }
+    }
+    pub fn pid(&self) -> i32 {
+        self.metadata().pid
+    }
+    pub fn mask(&self) -> MaskFlags {
+        MaskFlags::from_bits_truncate(self.metadata().mask)
+    }
+
+    /// Decodes the info records of this event. Metadata and fds are read in place, while
+    /// file handles, names and unknown records are copied out of the buffer.
+    pub fn event_info(&self) -> InfoRecords<'_> {
+        InfoRecords::new(self.buf, self.offset, &self.metadata())
+    }
+
+    // same as Event::forget_fd
+    pub fn forget_fd(&mut self) -> OwnedFd {
+        let fd = self.metadata().fd;
+        self.set_fd(libc::FAN_NOFD);
+
+        unsafe { OwnedFd::from_raw_fd(fd) }
+    }
+
+    // same as Event::pidfd
+    pub fn pidfd(&self) -> Option<BorrowedFd<'_>> {
+        match self.pidfd_info()? {
+            PidFdInfo::Fd(fd) => Some(unsafe { BorrowedFd::borrow_raw(fd) }),
+            _ => None,
+        }
+    }
+    pub fn pidfd_info(&self) -> Option<PidFdInfo> {
+        let offset = self.pidfd_offset()?;
+        Some(PidFdInfo::from_raw(read_struct(self.buf, offset).ok()?))
+    }
+    pub fn take_pidfd(&mut self) -> Option<OwnedFd> {
+        let PidFdInfo::Fd(fd) = self.pidfd_info()? else {
+            return None;
+        };
+        self.set_pidfd(libc::FAN_NOPIDFD);
+        Some(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
+    /// Copies this record into an owned [`Event`], which takes over the fd and the pidfd.
+    pub fn to_event(self) -> Result<Event, ParseError> {
+        self.try_to_event().map_err(|(_, error)| error)
+    }
+
+    // gives the record back on error, so its fd is still open, e.g. to answer it
+    pub(crate) fn try_to_event(mut self) -> Result<Event, (Self, ParseError)> {
+        let event_info = match self.event_info().collect::<Result<Vec<_>, _>>() {
+            Ok(event_info) => event_info,
+            Err(error) => return Err((self, error)),
+        };
+        let event = Event::new(self.metadata(), event_info);
+        self.set_fd(libc::FAN_NOFD);
+        self.set_pidfd(libc::FAN_NOPIDFD);
+        Ok(event)
+    }
+
+    fn set_fd(&mut self, fd: i32) {
+        const FD_OFFSET: usize = std::mem::offset_of!(libc::fanotify_event_metadata, fd);
+        let start = self.offset + FD_OFFSET;
+        self.buf[start..start + size_of::<i32>()].copy_from_slice(&fd.to_ne_bytes());
+    }
+
+    fn set_pidfd(&mut self, pidfd: i32) {
+        if let Some(start) = self.pidfd_offset() {
+            self.buf[start..start + size_of::<i32>()].copy_from_slice(&pidfd.to_ne_bytes());
+        }
+    }
+
+    // position of the pidfd inside a FAN_EVENT_INFO_TYPE_PIDFD record, without decoding the others
+    fn pidfd_offset(&self) -> Option<usize> {
+        const HEADER_SIZE: usize = size_of::<libc::fanotify_event_info_header>();
+        const PIDFD_OFFSET: usize = std::mem::offset_of!(libc::fanotify_event_info_pidfd, pidfd);
+
+        let mut offset = self.offset + self.metadata().metadata_len as usize;
+        while offset < self.buf.len() {
+            let header: libc::fanotify_event_info_header = read_struct(self.buf, offset).ok()?;
+            let len = header.len as usize;
+            if len < HEADER_SIZE || offset + len > self.buf.len() {
+                return None;
+            }
+            if header.info_type == libc::FAN_EVENT_INFO_TYPE_PIDFD
+                && len >= size_of::<libc::fanotify_event_info_pidfd>()
+            {
+                return Some(offset + PIDFD_OFFSET);
+            }
+            offset += len;
+        }
+        None
+    }
+}
+
+impl Drop for EventRef<'_> {
+    fn drop(&mut self) {
+        drop(self.take_pidfd());
+
+        let fd = self.metadata().fd;
+        if fd != libc::FAN_NOFD {
+            unsafe { libc::close(fd) };
+            self.set_fd(libc::FAN_NOFD);
+        }
+    }
+}
+
 #[cfg_attr(feature="libc-extra-traits", derive(Debug))]
 pub enum EventInfo {
-    Fid(libc::fanotify_event_info_fid),
-    PidFd(libc::fanotify_event_info_pidfd),
-    Error(libc::fanotify_event_info_error),
+    // FAN_REPORT_FID: the object the event happened on
+    Fid(FileHandle),
+    // FAN_REPORT_DIR_FID: the directory containing the object
+    Dfid(FileHandle),
+    // FAN_REPORT_DFID_NAME: the directory and the name of the entry within it
+    DfidName(FileHandle, OsString),
+    // FAN_RENAME with FAN_REPORT_DFID_NAME: where the entry was moved from and to
+    OldDfidName(FileHandle, OsString),
+    NewDfidName(FileHandle, OsString),
+    // FAN_REPORT_PIDFD, an open pidfd is owned by the event
+    PidFd(PidFdInfo),
+    // FAN_FS_ERROR
+    Error(FsError),
+    // pre-content events: the byte range about to be accessed
+    Range { offset: u64, count: u64 },
+    // FAN_MNT_ATTACH and FAN_MNT_DETACH: the unique id of the mount, as statx(2) STATX_MNT_ID_UNIQUE
+    Mount { mnt_id: u64 },
+    // record types added by newer kernels, kept as raw bytes (header included)
+    Unknown { info_type: u8, bytes: Vec<u8> },
+}
+
+impl EventInfo {
+    pub fn file_handle(&self) -> Option<&FileHandle> {
+        match self {
+            EventInfo::Fid(handle)
+            | EventInfo::Dfid(handle)
+            | EventInfo::DfidName(handle, _)
+            | EventInfo::OldDfidName(handle, _)
+            | EventInfo::NewDfidName(handle, _) => Some(handle),
+            _ => None,
+        }
+    }
+
+    // name of the directory entry, for the DFID_NAME family of records
+    pub fn name(&self) -> Option<&OsStr> {
+        match self {
+            EventInfo::DfidName(_, name)
+            | EventInfo::OldDfidName(_, name)
+            | EventInfo::NewDfidName(_, name) => Some(name),
+            _ => None,
+        }
+    }
+
+    // parse the info record at the start of `buf`, returning it together with its length.
+    // `offset` is the position of `buf` in the read buffer, only used for error reporting.
+    fn extract_from(buf: &[u8], offset: usize) -> Result<(Self, usize), ParseError> {
+        const HEADER_SIZE: usize = size_of::<libc::fanotify_event_info_header>();
+
+        let header: libc::fanotify_event_info_header =
+            read_struct(buf, 0).map_err(|_| ParseError::Truncated {
+                offset,
+                needed: HEADER_SIZE,
+                available: buf.len(),
+            })?;
+        let len = header.len as usize;
+        if len < HEADER_SIZE || len > buf.len() {
+            return Err(ParseError::BadLength { offset, len });
+        }
+        let record = &buf[..len];
+
+        let event_info = match Self::decode(header.info_type, record, offset) {
+            Err(ParseError::UnknownInfoType { info_type, .. }) => EventInfo::Unknown {
+                info_type,
+                bytes: record.to_vec(),
+            },
+            result => result?,
+        };
+        Ok((event_info, len))
+    }
+
+    fn decode(info_type: u8, record: &[u8], offset: usize) -> Result<Self, ParseError> {
+        // a record shorter than the struct of its type is malformed, not truncated
+        let bad_length = |_| ParseError::BadLength {
+            offset,
+            len: record.len(),
+        };
+        Ok(match info_type {
+            libc::FAN_EVENT_INFO_TYPE_FID => {
+                EventInfo::Fid(FileHandle::extract_from(record, offset)?.0)
+            }
+            libc::FAN_EVENT_INFO_TYPE_DFID => {
+                EventInfo::Dfid(FileHandle::extract_from(record, offset)?.0)
+            }
+            libc::FAN_EVENT_INFO_TYPE_DFID_NAME => {
+                let (handle, name) = FileHandle::extract_with_name(record, offset)?;
+                EventInfo::DfidName(handle, name)
+            }
+            libc::FAN_EVENT_INFO_TYPE_OLD_DFID_NAME => {
+                let (handle, name) = FileHandle::extract_with_name(record, offset)?;
+                EventInfo::OldDfidName(handle, name)
+            }
+            libc::FAN_EVENT_INFO_TYPE_NEW_DFID_NAME => {
+                let (handle, name) = FileHandle::extract_with_name(record, offset)?;
+                EventInfo::NewDfidName(handle, name)
+            }
+            libc::FAN_EVENT_INFO_TYPE_PIDFD => {
+                let pidfd: libc::fanotify_event_info_pidfd =
+                    read_struct(record, 0).map_err(bad_length)?;
+                EventInfo::PidFd(PidFdInfo::from_raw(pidfd.pidfd))
+            }
+            libc::FAN_EVENT_INFO_TYPE_ERROR => {
+                let error: libc::fanotify_event_info_error =
+                    read_struct(record, 0).map_err(bad_length)?;
+                EventInfo::Error(FsError {
+                    errno: Errno::new(error.error),
+                    error_count: error.error_count,
+                })
+            }
+            crate::consts::FAN_EVENT_INFO_TYPE_RANGE => {
+                let range: fanotify_event_info_range =
+                    read_struct(record, 0).map_err(bad_length)?;
+                EventInfo::Range {
+                    offset: range.offset,
+                    count: range.count,
+                }
+            }
+            crate::consts::FAN_EVENT_INFO_TYPE_MNT => {
+                let mnt: fanotify_event_info_mnt = read_struct(record, 0).map_err(bad_length)?;
+                EventInfo::Mount { mnt_id: mnt.mnt_id }
+            }
+            _ => return Err(ParseError::UnknownInfoType { offset, info_type }),
+        })
+    }
+}
+
+/// Filesystem error reported by `FAN_FS_ERROR`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FsError {
+    // the first error since the last one was read
+    pub errno: Errno,
+    // number of errors that happened since the last event was read, including this one
+    pub error_count: u32,
+}
+
+/// Pidfd reported with `FAN_REPORT_PIDFD`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PidFdInfo {
+    Fd(RawFd),
+    // FAN_NOPIDFD: the process exited before the event was read
+    NoPidFd,
+    // FAN_EPIDFD: creating the pidfd failed
+    Error,
+}
+
+impl PidFdInfo {
+    fn from_raw(pidfd: i32) -> Self {
+        match pidfd {
+            libc::FAN_NOPIDFD => PidFdInfo::NoPidFd,
+            libc::FAN_EPIDFD => PidFdInfo::Error,
+            fd if fd >= 0 => PidFdInfo::Fd(fd),
+            _ => PidFdInfo::Error,
+        }
+    }
+}
+
+/// Filesystem id of a file handle, the same value `statfs(2)` reports as `f_fsid`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Fsid(pub [i32; 2]);
+
+/// A `struct file_handle` reported in fid records, together with the filesystem it belongs to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FileHandle {
+    fsid: Fsid,
+    handle_type: i32,
+    f_handle: Vec<u8>,
+}
+
+impl FileHandle {
+    pub fn new(fsid: Fsid, handle_type: i32, f_handle: Vec<u8>) -> Self {
+        Self {
+            fsid,
+            handle_type,
+            f_handle,
+        }
+    }
+
+    pub fn fsid(&self) -> Fsid {
+        self.fsid
+    }
+    pub fn handle_type(&self) -> i32 {
+        self.handle_type
+    }
+    pub fn f_handle(&self) -> &[u8] {
+        &self.f_handle
+    }
+
+    // record layout:
+    //   struct fanotify_event_info_fid { hdr; fsid; }
+    //   struct file_handle { __u32 handle_bytes; int handle_type; unsigned char f_handle[]; }
+    //   [null terminated name, for the DFID_NAME family]
+    // returns the handle and the rest of the record after it
+    fn extract_from(record: &[u8], offset: usize) -> Result<(Self, &[u8]), ParseError> {
+        const FID_SIZE: usize = size_of::<libc::fanotify_event_info_fid>();
+        const HANDLE_HEADER_SIZE: usize = size_of::<u32>() + size_of::<i32>();
+
+        let bad_length = || ParseError::BadLength {
+            offset,
+            len: record.len(),
+        };
+        let fid: libc::fanotify_event_info_fid =
+            read_struct(record, 0).map_err(|_| bad_length())?;
+        let handle_bytes: u32 = read_struct(record, FID_SIZE).map_err(|_| bad_length())?;
+        let handle_type: i32 =
+            read_struct(record, FID_SIZE + size_of::<u32>()).map_err(|_| bad_length())?;
+
+        let start = FID_SIZE + HANDLE_HEADER_SIZE;
+        let end = start
+            .checked_add(handle_bytes as usize)
+            .filter(|end| *end <= record.len())
+            .ok_or_else(bad_length)?;
+        let handle = Self::new(Fsid(fid.fsid.val), handle_type, record[start..end].to_vec());
+        Ok((handle, &record[end..]))
+    }
+
+    fn extract_with_name(record: &[u8], offset: usize) -> Result<(Self, OsString), ParseError> {
+        let (handle, rest) = Self::extract_from(record, offset)?;
+        // the name is padded with zeros up to the record length
+        let name = match rest.iter().position(|&b| b == 0) {
+            Some(len) => &rest[..len],
+            None => {
+                return Err(ParseError::BadLength {
+                    offset,
+                    len: record.len(),
+                })
+            }
+        };
+        Ok((handle, OsStr::from_bytes(name).to_owned()))
+    }
+}
+
+// read and validate the metadata of the event record at `offset`.
+//
+// #define FAN_EVENT_OK(meta, len) \
+//   ((long)(len) >= (long)FAN_EVENT_METADATA_LEN)                && // rest buffer can contain a metadata struct
+//   (long)(meta) ->event_len >= (long)FAN_EVENT_METADATA_LEN     && // struct contains valid size
+//   (long)(meta) ->event_len <= (long)(len)                         // struct does not read over buffer boundary
+fn read_metadata(buf: &[u8], offset: usize) -> Result<libc::fanotify_event_metadata, ParseError> {
+    const EVENT_SIZE: usize = size_of::<libc::fanotify_event_metadata>();
+
+    let metadata: libc::fanotify_event_metadata = read_struct(buf, offset)?;
+    if metadata.vers != libc::FANOTIFY_METADATA_VERSION {
+        return Err(ParseError::UnsupportedVersion(metadata.vers));
+    }
+
+    let event_len = metadata.event_len as usize;
+    let metadata_len = metadata.metadata_len as usize;
+    if metadata_len < EVENT_SIZE || event_len < metadata_len {
+        return Err(ParseError::BadLength {
+            offset,
+            len: event_len,
+        });
+    }
+    if offset + event_len > buf.len() {
+        return Err(ParseError::Truncated {
+            offset,
+            needed: event_len,
+            available: buf.len() - offset,
+        });
+    }
+    Ok(metadata)
+}
+
+/// Iterator over the info records following the metadata of one event record.
+///
+/// Stops after the first malformed record.
+pub struct InfoRecords<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> InfoRecords<'a> {
+    // `buf` must end where the event record ends, `offset` is where the record starts
+    fn new(buf: &'a [u8], offset: usize, metadata: &libc::fanotify_event_metadata) -> Self {
+        Self {
+            buf,
+            offset: offset + metadata.metadata_len as usize,
+        }
+    }
+}
+
+impl Iterator for InfoRecords<'_> {
+    type Item = Result<EventInfo, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.buf.len() {
+            return None;
+        }
+        match EventInfo::extract_from(&self.buf[self.offset..], self.offset) {
+            Ok((event_info, len)) => {
+                self.offset += len;
+                Some(Ok(event_info))
+            }
+            Err(error) => {
+                self.offset = self.buf.len();
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+// copy a plain C struct out of `buf` at `offset`, which need not be aligned
+fn read_struct<T: Copy>(buf: &[u8], offset: usize) -> Result<T, ParseError> {
+    let needed = size_of::<T>();
+    let available = buf.len().saturating_sub(offset);
+    if available < needed {
+        return Err(ParseError::Truncated {
+            offset,
+            needed,
+            available,
+        });
+    }
+    Ok(unsafe { std::ptr::read_unaligned(buf.as_ptr().add(offset).cast()) })
 }
 
 // if tokio is enabled, Response need to be sendable across threads
 #[cfg_attr(feature="aio", derive(Clone, Copy))]
+#[repr(C)]
 pub struct Response {
     pub inner: libc::fanotify_response,
+    // written right after inner, only if inner.response has FAN_INFO
+    info: fanotify_response_info_audit_rule,
 }
 
 #[cfg(feature="aio")]
@@ -178,6 +740,334 @@ impl Response {
                 fd: fd.as_raw_fd(),
                 response,
             },
+            info: fanotify_response_info_audit_rule::default(),
+        }
+    }
+    pub fn from_verdict(fd: BorrowedFd, verdict: Verdict) -> Self {
+        Self::new(fd, verdict.response())
+    }
+    pub fn allow(fd: BorrowedFd) -> Self {
+        Self::new(fd, libc::FAN_ALLOW)
+    }
+    pub fn deny(fd: BorrowedFd) -> Self {
+        Self::new(fd, libc::FAN_DENY)
+    }
+
+    /// Denies the access with `errno` instead of `EPERM`.
+    ///
+    /// Needs Linux 6.14 and a `FAN_CLASS_PRE_CONTENT` group. The kernel only accepts a few
+    /// errnos, such as `EIO`, `EBUSY`, `ETXTBSY`, `EAGAIN`, `ENOSPC` and `EDQUOT`.
+    pub fn deny_with_errno(fd: BorrowedFd, errno: i32) -> Self {
+        Self::new(fd, fan_deny_errno(errno))
+    }
+
+    /// Asks the kernel to log the decision to the audit subsystem (`FAN_AUDIT`).
+    /// The group must have been created with `FAN_ENABLE_AUDIT`.
+    pub fn with_audit(mut self) -> Self {
+        self.inner.response |= libc::FAN_AUDIT;
+        self
+    }
+
+    /// Like [`Response::with_audit`], attaching the rule that made the decision (Linux 6.3).
+    pub fn with_audit_rule(mut self, rule: AuditRule) -> Self {
+        self.inner.response |= libc::FAN_AUDIT | libc::FAN_INFO;
+        self.info = fanotify_response_info_audit_rule {
+            hdr: fanotify_response_info_header {
+                type_: libc::FAN_RESPONSE_INFO_AUDIT_RULE,
+                pad: 0,
+                len: size_of::<fanotify_response_info_audit_rule>() as u16,
+            },
+            rule_number: rule.rule_number,
+            subj_trust: rule.subj_trust,
+            obj_trust: rule.obj_trust,
+        };
+        self
+    }
+
+    pub fn audit_rule(&self) -> Option<AuditRule> {
+        if self.inner.response & libc::FAN_INFO == 0 {
+            return None;
         }
+        Some(AuditRule {
+            rule_number: self.info.rule_number,
+            subj_trust: self.info.subj_trust,
+            obj_trust: self.info.obj_trust,
+        })
     }
-}
\ No newline at end of file
+
+    pub fn verdict(&self) -> Verdict {
+        if self.inner.response & libc::FAN_DENY != 0 {
+            Verdict::Deny
+        } else {
+            Verdict::Allow
+        }
+    }
+
+    // errno of a deny response, 0 for the default EPERM
+    pub fn errno(&self) -> i32 {
+        ((self.inner.response >> FAN_ERRNO_SHIFT) & FAN_ERRNO_MASK) as i32
+    }
+
+    // what gets written to the fanotify fd, the response and its info record in one buffer
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        let len = if self.inner.response & libc::FAN_INFO != 0 {
+            size_of::<Self>()
+        } else {
+            size_of::<libc::fanotify_response>()
+        };
+        unsafe { std::slice::from_raw_parts((self as *const Self).cast(), len) }
+    }
+}
+
+/// Audit rule attached to a response with [`Response::with_audit_rule`].
+///
+/// Trust values are `0` for no, `1` for yes and `2` for unknown, as used by fapolicyd.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct AuditRule {
+    pub rule_number: u32,
+    pub subj_trust: u32,
+    pub obj_trust: u32,
+}
+
+// not in libc yet, Linux 6.3
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct fanotify_response_info_header {
+    type_: u8,
+    pad: u8,
+    len: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct fanotify_response_info_audit_rule {
+    hdr: fanotify_response_info_header,
+    rule_number: u32,
+    subj_trust: u32,
+    obj_trust: u32,
+}
+
+// not in libc yet, Linux 6.14
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct fanotify_event_info_range {
+    hdr: libc::fanotify_event_info_header,
+    pad: u32,
+    offset: u64,
+    count: u64,
+}
+
+// not in libc yet, Linux 6.15
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct fanotify_event_info_mnt {
+    hdr: libc::fanotify_event_info_header,
+    mnt_id: u64,
+}
+
+/// Decision on a permission event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Verdict {
+    Allow,
+    Deny,
+}
+
+impl Verdict {
+    pub fn response(self) -> u32 {
+        match self {
+            Verdict::Allow => libc::FAN_ALLOW,
+            Verdict::Deny => libc::FAN_DENY,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::fd::BorrowedFd;
+
+    use super::{AuditRule, Event, EventBuffer, EventInfo, Fsid, Response};
+    use crate::{consts::FAN_EVENT_INFO_TYPE_RANGE, error::ParseError};
+
+    const EVENT_SIZE: usize = size_of::<libc::fanotify_event_metadata>();
+
+    fn metadata(event_len: usize) -> Vec<u8> {
+        let metadata = libc::fanotify_event_metadata {
+            event_len: event_len as u32,
+            vers: libc::FANOTIFY_METADATA_VERSION,
+            reserved: 0,
+            metadata_len: EVENT_SIZE as u16,
+            mask: libc::FAN_OPEN,
+            fd: libc::FAN_NOFD,
+            pid: 1,
+        };
+        unsafe {
+            std::slice::from_raw_parts(
+                (&metadata as *const libc::fanotify_event_metadata).cast(),
+                EVENT_SIZE,
+            )
+        }
+        .to_vec()
+    }
+
+    #[test]
+    fn test_extract_unknown_info() {
+        let mut buf = metadata(EVENT_SIZE + 8);
+        buf.extend_from_slice(&[0xff, 0, 8, 0, 1, 2, 3, 4]);
+        buf.extend(metadata(EVENT_SIZE));
+
+        let events = Event::extract_from(&buf).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].pid(), 1);
+        match &events[0].event_info[..] {
+            [EventInfo::Unknown { info_type, bytes }] => {
+                assert_eq!(*info_type, 0xff);
+                assert_eq!(bytes.len(), 8);
+            }
+            _ => panic!("expected a single unknown info record"),
+        }
+        assert!(events[1].event_info.is_empty());
+    }
+
+    #[test]
+    fn test_extract_range() {
+        let mut buf = metadata(EVENT_SIZE + 24);
+        buf.extend_from_slice(&[FAN_EVENT_INFO_TYPE_RANGE, 0, 24, 0, 0, 0, 0, 0]);
+        buf.extend(4096u64.to_ne_bytes());
+        buf.extend(512u64.to_ne_bytes());
+
+        let events = Event::extract_from(&buf).unwrap();
+        assert_eq!(events[0].range(), Some((4096, 512)));
+    }
+
+    #[test]
+    fn test_extract_malformed() {
+        let buf = metadata(EVENT_SIZE + 8);
+        assert!(matches!(
+            Event::extract_from(&buf),
+            Err(ParseError::Truncated { offset: 0, .. })
+        ));
+
+        let mut buf = metadata(EVENT_SIZE + 8);
+        buf.extend_from_slice(&[libc::FAN_EVENT_INFO_TYPE_PIDFD, 0, 64, 0, 0, 0, 0, 0]);
+        assert!(matches!(
+            Event::extract_from(&buf),
+            Err(ParseError::BadLength { len: 64, .. })
+        ));
+
+        let mut buf = metadata(EVENT_SIZE);
+        buf[4] = libc::FANOTIFY_METADATA_VERSION + 1;
+        assert_eq!(
+            Event::extract_from(&buf).err(),
+            Some(ParseError::UnsupportedVersion(
+                libc::FANOTIFY_METADATA_VERSION + 1
+            ))
+        );
+
+        // header, fsid, a handle_bytes past the end of any buffer
+        let mut buf = metadata(EVENT_SIZE + 20);
+        buf.extend_from_slice(&[libc::FAN_EVENT_INFO_TYPE_FID, 0, 20, 0]);
+        buf.extend([0; 8]);
+        buf.extend(u32::MAX.to_ne_bytes());
+        buf.extend(1i32.to_ne_bytes());
+        assert!(matches!(
+            Event::extract_from(&buf),
+            Err(ParseError::BadLength { len: 20, .. })
+        ));
+    }
+
+    #[test]
+    fn test_extract_all_keeps_events() {
+        let mut buf = metadata(EVENT_SIZE);
+        buf.extend(metadata(EVENT_SIZE + 8));
+        buf.extend_from_slice(&[libc::FAN_EVENT_INFO_TYPE_PIDFD, 0, 64, 0, 0, 0, 0, 0]);
+        buf.extend(metadata(EVENT_SIZE));
+        buf.extend_from_slice(&[0; 8]);
+
+        let (events, error) = Event::extract_all(&buf);
+        assert_eq!(events.len(), 3);
+        assert!(events.iter().all(|event| event.event_info.is_empty()));
+        assert!(matches!(error, Some(ParseError::BadLength { len: 64, .. })));
+    }
+
+    #[test]
+    fn test_event_iter() {
+        let mut buf = metadata(EVENT_SIZE);
+        buf.extend(metadata(EVENT_SIZE + 4));
+        buf.extend_from_slice(&[0xff, 0, 4, 0]);
+
+        let mut buffer = EventBuffer::with_capacity(buf.len());
+        buffer.as_mut_slice().copy_from_slice(&buf);
+        let mut events = buffer.events(buf.len());
+
+        let event = events.next().unwrap().unwrap();
+        assert!(event.fd().is_none());
+        assert_eq!(event.event_info().count(), 0);
+        drop(event);
+
+        let event = events.next().unwrap().unwrap().to_event().unwrap();
+        assert!(matches!(
+            &event.event_info[..],
+            [EventInfo::Unknown {
+                info_type: 0xff,
+                ..
+            }]
+        ));
+        assert!(events.next().is_none());
+    }
+
+    #[test]
+    fn test_extract_dfid_name() {
+        // header, fsid, handle_bytes, handle_type, f_handle, name padded to 4 bytes
+        let mut record = vec![libc::FAN_EVENT_INFO_TYPE_OLD_DFID_NAME, 0, 32, 0];
+        record.extend(1i32.to_ne_bytes());
+        record.extend(2i32.to_ne_bytes());
+        record.extend(4u32.to_ne_bytes());
+        record.extend(1i32.to_ne_bytes());
+        record.extend([0xaa, 0xbb, 0xcc, 0xdd]);
+        record.extend(b"file.txt");
+
+        let mut buf = metadata(EVENT_SIZE + record.len());
+        buf.extend(&record);
+        assert!(matches!(
+            Event::extract_from(&buf),
+            Err(ParseError::BadLength { .. })
+        ));
+
+        record[2] = 36;
+        record.extend([0; 4]);
+        let mut buf = metadata(EVENT_SIZE + record.len());
+        buf.extend(&record);
+        let events = Event::extract_from(&buf).unwrap();
+        let [event_info @ EventInfo::OldDfidName(..)] = &events[0].event_info[..] else {
+            panic!("expected a single old dfid name record");
+        };
+        let handle = event_info.file_handle().unwrap();
+        assert_eq!(handle.fsid(), Fsid([1, 2]));
+        assert_eq!(handle.handle_type(), 1);
+        assert_eq!(handle.f_handle(), &[0xaa, 0xbb, 0xcc, 0xdd]);
+        assert_eq!(event_info.name(), Some("file.txt".as_ref()));
+    }
+
+    #[test]
+    fn test_response_bytes() {
+        let fd = unsafe { BorrowedFd::borrow_raw(3) };
+        let response = Response::deny_with_errno(fd, libc::EIO);
+        assert_eq!(response.errno(), libc::EIO);
+        assert_eq!(response.as_bytes().len(), 8);
+        assert_eq!(response.inner.response & 0xff, libc::FAN_DENY);
+
+        let rule = AuditRule {
+            rule_number: 7,
+            subj_trust: 1,
+            obj_trust: 2,
+        };
+        let response = Response::allow(fd).with_audit_rule(rule);
+        assert_eq!(response.audit_rule(), Some(rule));
+        let bytes = response.as_bytes();
+        assert_eq!(bytes.len(), 24);
+        // header: type, pad, len
+        assert_eq!(bytes[8], libc::FAN_RESPONSE_INFO_AUDIT_RULE);
+        assert_eq!(&bytes[10..12], &16u16.to_ne_bytes());
+        assert_eq!(&bytes[12..16], &7u32.to_ne_bytes());
+    }
+}
@@ -0,0 +1,34 @@
+use std::os::fd::{AsRawFd, OwnedFd};
+
+use ::mio::{event::Source, unix::SourceFd, Interest, Registry, Token};
+
+use crate::fanotify::Fanotify;
+
+/// Lets a group be polled by a mio event loop.
+///
+/// The group has to be initialized with `FAN_NONBLOCK`, `read_events` and `read_into` then
+/// fail with [`std::io::ErrorKind::WouldBlock`] once the queue is drained. Register it for
+/// [`Interest::READABLE`], answering doesn't wait, see [`Fanotify::write_response`].
+impl Source for Fanotify<OwnedFd> {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        SourceFd(&self.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        SourceFd(&self.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> std::io::Result<()> {
+        SourceFd(&self.as_raw_fd()).deregister(registry)
+    }
+}
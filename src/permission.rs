@@ -0,0 +1,203 @@
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+
+use crate::{
+    fanotify::Fanotify,
+    messages::{Event, Response, Verdict},
+};
+
+/// A permission event that is guaranteed to be answered.
+///
+/// The process accessing the file is blocked until the kernel gets a response, so if the guard
+/// is dropped without an answer, e.g. on an early return or a panic, the default verdict is
+/// written instead. The guard holds its own duplicate of the fanotify fd, so it can be sent to
+/// and answered on another thread, independently of the `Fanotify` it came from.
+pub struct PermissionRequest {
+    event: Event,
+    fanotify_fd: OwnedFd,
+    default: Verdict,
+    answered: bool,
+}
+
+impl Event {
+    /// Wraps a permission event read from `fan` into a [`PermissionRequest`].
+    ///
+    /// Fails for events that are not permission events or carry no fd. If duplicating the
+    /// fanotify fd fails, `default` is answered right away before the error is returned.
+    pub fn into_permission_request<F: AsRawFd>(
+        self,
+        fan: &Fanotify<F>,
+        default: Verdict,
+    ) -> std::io::Result<PermissionRequest> {
+        if !self.mask().is_permission_event() || self.fd().is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "not a permission event",
+            ));
+        }
+
+        let fan = unsafe { BorrowedFd::borrow_raw(fan.as_raw_fd()) };
+        let fanotify_fd = match fan.try_clone_to_owned() {
+            Ok(fd) => fd,
+            Err(error) => {
+                if let Some(fd) = self.fd() {
+                    let _ = write_response(fan, &Response::from_verdict(fd, default));
+                }
+                return Err(error);
+            }
+        };
+        Ok(PermissionRequest {
+            event: self,
+            fanotify_fd,
+            default,
+            answered: false,
+        })
+    }
+}
+
+impl PermissionRequest {
+    pub fn event(&self) -> &Event {
+        &self.event
+    }
+    pub fn fd(&self) -> BorrowedFd<'_> {
+        // checked in Event::into_permission_request
+        self.event.fd().unwrap()
+    }
+    pub fn pid(&self) -> i32 {
+        self.event.pid()
+    }
+    pub fn default_verdict(&self) -> Verdict {
+        self.default
+    }
+    pub fn set_default_verdict(&mut self, default: Verdict) {
+        self.default = default;
+    }
+
+    pub fn allow(self) -> std::io::Result<()> {
+        self.respond(Verdict::Allow)
+    }
+    pub fn deny(self) -> std::io::Result<()> {
+        self.respond(Verdict::Deny)
+    }
+    pub fn respond(self, verdict: Verdict) -> std::io::Result<()> {
+        let response = self.response(verdict);
+        self.respond_with(response)
+    }
+
+    /// A response to this request, to be extended with audit info or an errno
+    /// and sent with [`PermissionRequest::respond_with`].
+    pub fn response(&self, verdict: Verdict) -> Response {
+        Response::from_verdict(self.fd(), verdict)
+    }
+    pub fn respond_with(mut self, response: Response) -> std::io::Result<()> {
+        // even if writing fails there is nothing left to answer on drop
+        self.answered = true;
+        write_response(self.fanotify_fd.as_fd(), &response)
+    }
+
+    // drops the request without answering, because it was answered elsewhere
+    pub(crate) fn dismiss(mut self) {
+        self.answered = true;
+    }
+}
+
+/// Decides permission requests, e.g. for a [`PermissionDispatcher`] or a [`Policy`].
+///
+/// Implemented for closures taking a request and returning a verdict.
+///
+/// [`PermissionDispatcher`]: crate::dispatcher::PermissionDispatcher
+/// [`Policy`]: crate::policy::Policy
+pub trait PermissionHandler {
+    fn decide(&self, request: &PermissionRequest) -> Verdict;
+
+    /// The response to write, the decided verdict unless overridden, e.g. to add audit info,
+    /// and whether it holds for every process accessing the file until it is modified, so it
+    /// can be kept in a [`VerdictCache`]. Not cacheable by default.
+    ///
+    /// [`VerdictCache`]: crate::cache::VerdictCache
+    fn response(&self, request: &PermissionRequest) -> (Response, bool) {
+        (request.response(self.decide(request)), false)
+    }
+}
+
+impl<F> PermissionHandler for F
+where
+    F: Fn(&PermissionRequest) -> Verdict,
+{
+    fn decide(&self, request: &PermissionRequest) -> Verdict {
+        self(request)
+    }
+}
+
+impl Drop for PermissionRequest {
+    fn drop(&mut self) {
+        if !self.answered {
+            let response = self.response(self.default);
+            let _ = write_response(self.fanotify_fd.as_fd(), &response);
+        }
+    }
+}
+
+fn write_response(fan: BorrowedFd, response: &Response) -> std::io::Result<()> {
+    let bytes = response.as_bytes();
+    let nwrite = unsafe { libc::write(fan.as_raw_fd(), bytes.as_ptr().cast(), bytes.len()) };
+    if nwrite < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::Read,
+        os::fd::{FromRawFd, IntoRawFd, OwnedFd},
+    };
+
+    use crate::{
+        consts::InitFlags,
+        fanotify::Fanotify,
+        messages::{Event, Verdict},
+    };
+
+    #[test]
+    fn test_drop_answers_default() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (mut reader, writer) = unsafe {
+            (
+                std::fs::File::from_raw_fd(fds[0]),
+                OwnedFd::from_raw_fd(fds[1]),
+            )
+        };
+        // answered through the pipe in place of a fanotify group
+        let fan = Fanotify::new(writer, InitFlags::empty());
+
+        let fd = std::fs::File::open("/dev/null").unwrap().into_raw_fd();
+        let metadata = libc::fanotify_event_metadata {
+            event_len: size_of::<libc::fanotify_event_metadata>() as u32,
+            vers: libc::FANOTIFY_METADATA_VERSION,
+            reserved: 0,
+            metadata_len: size_of::<libc::fanotify_event_metadata>() as u16,
+            mask: libc::FAN_OPEN_PERM | libc::FAN_ONDIR,
+            fd,
+            pid: 1,
+        };
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                (&metadata as *const libc::fanotify_event_metadata).cast::<u8>(),
+                size_of::<libc::fanotify_event_metadata>(),
+            )
+        };
+        let event = Event::extract_from(bytes).unwrap().pop().unwrap();
+        let request = event.into_permission_request(&fan, Verdict::Deny).unwrap();
+        drop(request);
+
+        let mut response = [0u8; 8];
+        reader.read_exact(&mut response).unwrap();
+        assert_eq!(i32::from_ne_bytes(response[..4].try_into().unwrap()), fd);
+        assert_eq!(
+            u32::from_ne_bytes(response[4..].try_into().unwrap()),
+            libc::FAN_DENY
+        );
+    }
+}
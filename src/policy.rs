@@ -0,0 +1,621 @@
+use std::{
+    cell::OnceCell,
+    fs::Metadata,
+    os::{fd::AsRawFd, unix::ffi::OsStrExt},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    consts::MaskFlags,
+    messages::{AuditRule, Response, Verdict},
+    permission::{PermissionHandler, PermissionRequest},
+};
+
+/// Rule based [`PermissionHandler`], the first rule matching a request decides it.
+///
+/// ```no_run
+/// use fanotify::policy::{Access, Policy, Rule};
+/// use fanotify::prelude::Verdict;
+///
+/// let policy = Policy::new(Verdict::Allow)
+///     .rule(Rule::allow().exe("/usr/bin/backup"))
+///     .rule(Rule::deny().path_glob("/srv/secrets/**").access(Access::Open).audit());
+/// ```
+pub struct Policy {
+    rules: Vec<Rule>,
+    default: Verdict,
+}
+
+/// Verdict for the requests matching all of its conditions, a rule without conditions
+/// matches every request.
+pub struct Rule {
+    verdict: Verdict,
+    conditions: Vec<Condition>,
+    audit: Option<Audit>,
+}
+
+/// Kind of access a permission event asks for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Access {
+    /// `FAN_OPEN_PERM`
+    Open,
+    /// `FAN_ACCESS_PERM` and `FAN_PRE_ACCESS`
+    Access,
+    /// `FAN_OPEN_EXEC_PERM`
+    Exec,
+}
+
+impl Access {
+    pub fn mask(self) -> MaskFlags {
+        match self {
+            Access::Open => MaskFlags::FAN_OPEN_PERM,
+            Access::Access => MaskFlags::FAN_ACCESS_PERM | MaskFlags::FAN_PRE_ACCESS,
+            Access::Exec => MaskFlags::FAN_OPEN_EXEC_PERM,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Audit {
+    Plain,
+    Rule(AuditRule),
+}
+
+enum PathPattern {
+    Exact(PathBuf),
+    // whole components only, /usr/lib doesn't match /usr/lib64
+    Prefix(PathBuf),
+    Glob(String),
+}
+
+impl PathPattern {
+    fn matches(&self, path: &Path) -> bool {
+        match self {
+            PathPattern::Exact(exact) => path == exact,
+            PathPattern::Prefix(prefix) => path.starts_with(prefix),
+            PathPattern::Glob(glob) => glob_match(glob.as_bytes(), path.as_os_str().as_bytes()),
+        }
+    }
+}
+
+enum Condition {
+    Path(PathPattern),
+    Exe(PathPattern),
+    Uid(u32),
+    Gid(u32),
+    AncestorExe(PathPattern),
+    AncestorPid(i32),
+    Access(Access),
+    Metadata(Box<dyn Fn(&Metadata) -> bool + Send + Sync>),
+}
+
+impl Policy {
+    /// A policy answering `default` when no rule matches.
+    pub fn new(default: Verdict) -> Self {
+        Self {
+            rules: Vec::new(),
+            default,
+        }
+    }
+
+    /// Appends a rule, rules are tried in the order they were added.
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Index of the first rule matching `request`.
+    pub fn matching_rule(&self, request: &PermissionRequest) -> Option<usize> {
+        let subject = Subject::new(request);
+        self.rules.iter().position(|rule| rule.matches(&subject))
+    }
+}
+
+impl PermissionHandler for Policy {
+    fn decide(&self, request: &PermissionRequest) -> Verdict {
+        match self.matching_rule(request) {
+            Some(index) => self.rules[index].verdict,
+            None => self.default,
+        }
+    }
+
+    // cacheable when no rule up to the deciding one looks at the process, at metadata, which
+    // can change without modifying the file, or at the path, which changes with a rename or
+    // a hard link
+    fn response(&self, request: &PermissionRequest) -> (Response, bool) {
+        let index = self.matching_rule(request);
+        let decided = match index {
+            Some(index) => &self.rules[..=index],
+            None => &self.rules[..],
+        };
+        let cacheable = decided.iter().all(|rule| {
+            rule.audit.is_none()
+                && rule
+                    .conditions
+                    .iter()
+                    .all(|condition| matches!(condition, Condition::Access(_)))
+        });
+
+        let Some(rule) = index.map(|index| &self.rules[index]) else {
+            return (request.response(self.default), cacheable);
+        };
+        let response = request.response(rule.verdict);
+        let response = match rule.audit {
+            Some(Audit::Plain) => response.with_audit(),
+            Some(Audit::Rule(audit_rule)) => response.with_audit_rule(audit_rule),
+            None => response,
+        };
+        (response, cacheable)
+    }
+}
+
+impl Rule {
+    pub fn new(verdict: Verdict) -> Self {
+        Self {
+            verdict,
+            conditions: Vec::new(),
+            audit: None,
+        }
+    }
+    pub fn allow() -> Self {
+        Self::new(Verdict::Allow)
+    }
+    pub fn deny() -> Self {
+        Self::new(Verdict::Deny)
+    }
+
+    pub fn verdict(&self) -> Verdict {
+        self.verdict
+    }
+
+    /// Files at or below `prefix`, compared by whole path components.
+    pub fn path_prefix<P: Into<PathBuf>>(self, prefix: P) -> Self {
+        self.when(Condition::Path(PathPattern::Prefix(prefix.into())))
+    }
+
+    /// Files whose path matches `glob`, see [`Rule::exe_glob`] for the syntax.
+    pub fn path_glob(self, glob: &str) -> Self {
+        self.when(Condition::Path(PathPattern::Glob(glob.to_owned())))
+    }
+
+    /// Accesses by the program at `exe`.
+    pub fn exe<P: Into<PathBuf>>(self, exe: P) -> Self {
+        self.when(Condition::Exe(PathPattern::Exact(exe.into())))
+    }
+
+    /// Accesses by a program whose path matches `glob`. `*` and `?` match within a path
+    /// component, `**` across components, and `[...]` one of the listed characters, or any
+    /// other one with a leading `!`.
+    pub fn exe_glob(self, glob: &str) -> Self {
+        self.when(Condition::Exe(PathPattern::Glob(glob.to_owned())))
+    }
+
+    /// Accesses by processes with the effective user id `uid`.
+    pub fn uid(self, uid: u32) -> Self {
+        self.when(Condition::Uid(uid))
+    }
+
+    /// Accesses by processes with the effective group id `gid`.
+    pub fn gid(self, gid: u32) -> Self {
+        self.when(Condition::Gid(gid))
+    }
+
+    /// Accesses by descendants of a process running the program at `exe`.
+    pub fn ancestor_exe<P: Into<PathBuf>>(self, exe: P) -> Self {
+        self.when(Condition::AncestorExe(PathPattern::Exact(exe.into())))
+    }
+
+    /// Accesses by descendants of the process `pid`.
+    pub fn ancestor_pid(self, pid: i32) -> Self {
+        self.when(Condition::AncestorPid(pid))
+    }
+
+    pub fn access(self, access: Access) -> Self {
+        self.when(Condition::Access(access))
+    }
+
+    /// Files whose metadata satisfies `predicate`, e.g. their owner or size.
+    pub fn metadata<F>(self, predicate: F) -> Self
+    where
+        F: Fn(&Metadata) -> bool + Send + Sync + 'static,
+    {
+        self.when(Condition::Metadata(Box::new(predicate)))
+    }
+
+    /// Logs decisions made by this rule to the audit subsystem (`FAN_AUDIT`),
+    /// which needs a group created with `FAN_ENABLE_AUDIT`.
+    pub fn audit(mut self) -> Self {
+        self.audit = Some(Audit::Plain);
+        self
+    }
+
+    /// Like [`Rule::audit`], tagging the log entries with `rule` (Linux 6.3).
+    pub fn audit_rule(mut self, rule: AuditRule) -> Self {
+        self.audit = Some(Audit::Rule(rule));
+        self
+    }
+
+    fn when(mut self, condition: Condition) -> Self {
+        self.conditions.push(condition);
+        self
+    }
+
+    // details that can't be looked up, e.g. because the process exited, match nothing
+    fn matches(&self, subject: &Subject) -> bool {
+        self.conditions.iter().all(|condition| match condition {
+            Condition::Path(pattern) => subject.path().is_some_and(|path| pattern.matches(path)),
+            Condition::Exe(pattern) => subject.exe().is_some_and(|exe| pattern.matches(exe)),
+            Condition::Uid(uid) => subject.ids().is_some_and(|(euid, _)| euid == *uid),
+            Condition::Gid(gid) => subject.ids().is_some_and(|(_, egid)| egid == *gid),
+            Condition::AncestorExe(pattern) => subject
+                .ancestors()
+                .iter()
+                .any(|(_, exe)| exe.as_deref().is_some_and(|exe| pattern.matches(exe))),
+            Condition::AncestorPid(pid) => subject
+                .ancestors()
+                .iter()
+                .any(|(ancestor, _)| ancestor == pid),
+            Condition::Access(access) => subject.request.event().mask().intersects(access.mask()),
+            Condition::Metadata(predicate) => subject.metadata().is_some_and(predicate),
+        })
+    }
+}
+
+// details of a request looked up on first use, shared by the rules of one evaluation
+struct Subject<'a> {
+    request: &'a PermissionRequest,
+    path: OnceCell<Option<PathBuf>>,
+    exe: OnceCell<Option<PathBuf>>,
+    ids: OnceCell<Option<(u32, u32)>>,
+    ancestors: OnceCell<Vec<(i32, Option<PathBuf>)>>,
+    metadata: OnceCell<Option<Metadata>>,
+}
+
+impl<'a> Subject<'a> {
+    fn new(request: &'a PermissionRequest) -> Self {
+        Self {
+            request,
+            path: OnceCell::new(),
+            exe: OnceCell::new(),
+            ids: OnceCell::new(),
+            ancestors: OnceCell::new(),
+            metadata: OnceCell::new(),
+        }
+    }
+
+    fn fd_path(&self) -> String {
+        format!("/proc/self/fd/{}", self.request.fd().as_raw_fd())
+    }
+
+    fn path(&self) -> Option<&Path> {
+        self.path
+            .get_or_init(|| std::fs::read_link(self.fd_path()).ok())
+            .as_deref()
+    }
+
+    fn exe(&self) -> Option<&Path> {
+        self.exe.get_or_init(|| exe(self.request.pid())).as_deref()
+    }
+
+    fn ids(&self) -> Option<(u32, u32)> {
+        *self.ids.get_or_init(|| {
+            let status = std::fs::read_to_string(format!("/proc/{}/status", self.request.pid()));
+            let status = status.ok()?;
+            // real, effective, saved and filesystem ids
+            let effective = |key: &str| -> Option<u32> {
+                let line = status.lines().find_map(|line| line.strip_prefix(key))?;
+                line.split_whitespace().nth(1)?.parse().ok()
+            };
+            Some((effective("Uid:")?, effective("Gid:")?))
+        })
+    }
+
+    // parent first, up to init
+    fn ancestors(&self) -> &[(i32, Option<PathBuf>)] {
+        self.ancestors.get_or_init(|| {
+            let mut ancestors = Vec::new();
+            let mut pid = self.request.pid();
+            // bounded in case a pid is reused while walking
+            while ancestors.len() < 64 {
+                let Some(parent) = parent(pid).filter(|parent| *parent > 0) else {
+                    break;
+                };
+                ancestors.push((parent, exe(parent)));
+                pid = parent;
+            }
+            ancestors
+        })
+    }
+
+    // follows the magic link to the open file, even if it was deleted since
+    fn metadata(&self) -> Option<&Metadata> {
+        self.metadata
+            .get_or_init(|| std::fs::metadata(self.fd_path()).ok())
+            .as_ref()
+    }
+}
+
+fn exe(pid: i32) -> Option<PathBuf> {
+    std::fs::read_link(format!("/proc/{pid}/exe")).ok()
+}
+
+fn parent(pid: i32) -> Option<i32> {
+    let status = std::fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+    let ppid = status.lines().find_map(|line| line.strip_prefix("PPid:"))?;
+    ppid.trim().parse().ok()
+}
+
+enum Token<'a> {
+    Literal(u8),
+    // `?`
+    Any,
+    // `*`
+    Star,
+    // `**`
+    Globstar,
+    // `**/`, also matching no directory at all
+    GlobstarSlash,
+    Class { negated: bool, set: &'a [u8] },
+}
+
+fn tokenize(glob: &[u8]) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = glob;
+    while !rest.is_empty() {
+        let (token, len) = match rest {
+            [b'*', b'*', b'/', ..] => (Token::GlobstarSlash, 3),
+            [b'*', b'*', ..] => (Token::Globstar, 2),
+            [b'*', ..] => (Token::Star, 1),
+            [b'?', ..] => (Token::Any, 1),
+            [b'[', class @ ..] => {
+                match class.iter().skip(1).position(|c| *c == b']') {
+                    Some(end) => {
+                        let (negated, set) = match &class[..end + 1] {
+                            [b'!', set @ ..] => (true, set),
+                            set => (false, set),
+                        };
+                        (Token::Class { negated, set }, end + 3)
+                    }
+                    // no closing bracket, a literal '['
+                    None => (Token::Literal(b'['), 1),
+                }
+            }
+            [c, ..] => (Token::Literal(*c), 1),
+            [] => unreachable!(),
+        };
+        tokens.push(token);
+        rest = &rest[len..];
+    }
+    tokens
+}
+
+// shell style matching on bytes, `**` is the only wildcard crossing '/'.
+// Paths come from whoever triggers the event, so no backtracking: going from the last token
+// to the first, `matched[p]` tells whether the tokens from the current one on match path[p..]
+fn glob_match(glob: &[u8], path: &[u8]) -> bool {
+    let mut next = vec![false; path.len() + 1];
+    next[path.len()] = true;
+    let mut matched = vec![false; path.len() + 1];
+    for token in tokenize(glob).iter().rev() {
+        // matched[j + 1] for the first '/' at j >= p
+        let mut after_slash = false;
+        for p in (0..=path.len()).rev() {
+            let c = path.get(p).copied();
+            let in_component = c.is_some_and(|c| c != b'/');
+            matched[p] = match token {
+                Token::Literal(literal) => c == Some(*literal) && next[p + 1],
+                Token::Any => in_component && next[p + 1],
+                Token::Class { negated, set } => {
+                    in_component && class_contains(set, c.unwrap()) != *negated && next[p + 1]
+                }
+                Token::Star => next[p] || (in_component && matched[p + 1]),
+                Token::Globstar => next[p] || (c.is_some() && matched[p + 1]),
+                Token::GlobstarSlash => {
+                    if c == Some(b'/') {
+                        after_slash = matched[p + 1];
+                    }
+                    next[p] || after_slash
+                }
+            };
+        }
+        std::mem::swap(&mut next, &mut matched);
+    }
+    next[0]
+}
+
+fn class_contains(set: &[u8], c: u8) -> bool {
+    let mut i = 0;
+    while i < set.len() {
+        if i + 2 < set.len() && set[i + 1] == b'-' {
+            if (set[i]..=set[i + 2]).contains(&c) {
+                return true;
+            }
+            i += 3;
+        } else {
+            if set[i] == c {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        os::fd::{FromRawFd, IntoRawFd, OwnedFd},
+        path::Path,
+    };
+
+    use super::{glob_match, Access, PathPattern, Policy, Rule};
+    use crate::{
+        consts::InitFlags,
+        fanotify::Fanotify,
+        messages::{AuditRule, Event, Response, Verdict},
+        permission::{PermissionHandler, PermissionRequest},
+    };
+
+    fn matches(glob: &str, path: &str) -> bool {
+        glob_match(glob.as_bytes(), path.as_bytes())
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(matches("/usr/bin/*", "/usr/bin/ls"));
+        assert!(!matches("/usr/bin/*", "/usr/bin/x/ls"));
+        assert!(matches("/usr/bin/*sh", "/usr/bin/bash"));
+        assert!(matches("/usr/bin/*sh", "/usr/bin/sh"));
+        assert!(!matches("/usr/bin/*sh", "/usr/bin/shell"));
+
+        assert!(matches("/srv/**", "/srv/a/b/c"));
+        assert!(matches("/srv/**/*.key", "/srv/tls.key"));
+        assert!(matches("/srv/**/*.key", "/srv/a/b/tls.key"));
+        assert!(!matches("/srv/**/*.key", "/srv/a/b/tls.pem"));
+        assert!(!matches("/srv/**", "/srvx/a"));
+
+        assert!(matches("/dev/tty?", "/dev/tty1"));
+        assert!(!matches("/dev/tty?", "/dev/tty"));
+        assert!(!matches("/a?b", "/a/b"));
+
+        assert!(matches("/dev/sd[a-c]", "/dev/sdb"));
+        assert!(!matches("/dev/sd[a-c]", "/dev/sdd"));
+        assert!(matches("/dev/sd[!a-c]", "/dev/sdd"));
+        assert!(matches("/x/[]]", "/x/]"));
+        assert!(matches("/x/[", "/x/["));
+
+        assert!(matches("/etc/passwd", "/etc/passwd"));
+        assert!(!matches("/etc/passwd", "/etc/passwd-"));
+        assert!(matches("", ""));
+        assert!(!matches("", "/"));
+    }
+
+    #[test]
+    fn test_glob_match_many_wildcards() {
+        // exponential with backtracking
+        let path = format!("/{}b", "a/".repeat(2000));
+        assert!(!matches(&"**/*a*".repeat(20), &path));
+        assert!(!matches(
+            &"*a".repeat(40),
+            &format!("{}b", "a".repeat(4000))
+        ));
+        assert!(matches("/**/**/**/**/a/b", &path));
+    }
+
+    #[test]
+    fn test_path_prefix() {
+        let prefix = PathPattern::Prefix("/usr/lib".into());
+        assert!(prefix.matches(Path::new("/usr/lib")));
+        assert!(prefix.matches(Path::new("/usr/lib/libc.so")));
+        assert!(!prefix.matches(Path::new("/usr/lib64/libc.so")));
+    }
+
+    // a request as if this process opened `path`, answered through a pipe
+    fn request(path: &str, mask: u64) -> PermissionRequest {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let fan = unsafe {
+            libc::close(fds[0]);
+            Fanotify::new(OwnedFd::from_raw_fd(fds[1]), InitFlags::empty())
+        };
+        let metadata = libc::fanotify_event_metadata {
+            event_len: size_of::<libc::fanotify_event_metadata>() as u32,
+            vers: libc::FANOTIFY_METADATA_VERSION,
+            reserved: 0,
+            metadata_len: size_of::<libc::fanotify_event_metadata>() as u16,
+            mask,
+            fd: std::fs::File::open(path).unwrap().into_raw_fd(),
+            pid: std::process::id() as i32,
+        };
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                (&metadata as *const libc::fanotify_event_metadata).cast::<u8>(),
+                size_of::<libc::fanotify_event_metadata>(),
+            )
+        };
+        let event = Event::extract_from(bytes).unwrap().pop().unwrap();
+        event.into_permission_request(&fan, Verdict::Allow).unwrap()
+    }
+
+    fn respond(policy: &Policy, path: &str) -> (Response, bool) {
+        policy.response(&request(path, libc::FAN_OPEN_PERM))
+    }
+
+    #[test]
+    fn test_first_matching_rule() {
+        let policy = Policy::new(Verdict::Allow)
+            .rule(Rule::deny().path_prefix("/dev/null"))
+            .rule(Rule::allow().path_prefix("/dev"))
+            .rule(Rule::deny());
+        assert_eq!(respond(&policy, "/dev/null").0.verdict(), Verdict::Deny);
+        assert_eq!(respond(&policy, "/dev/zero").0.verdict(), Verdict::Allow);
+        assert_eq!(
+            policy.matching_rule(&request("/dev/zero", libc::FAN_OPEN_PERM)),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_default_verdict() {
+        let policy = Policy::new(Verdict::Deny).rule(Rule::allow().path_glob("/etc/**"));
+        assert_eq!(respond(&policy, "/dev/null").0.verdict(), Verdict::Deny);
+        let policy = Policy::new(Verdict::Allow)
+            .rule(Rule::deny().access(Access::Exec))
+            .rule(Rule::deny().uid(u32::MAX));
+        assert_eq!(respond(&policy, "/dev/null").0.verdict(), Verdict::Allow);
+    }
+
+    #[test]
+    fn test_audit() {
+        let rule = AuditRule {
+            rule_number: 7,
+            subj_trust: 2,
+            obj_trust: 1,
+        };
+        let policy = Policy::new(Verdict::Allow)
+            .rule(Rule::deny().path_prefix("/dev/null").audit())
+            .rule(Rule::deny().path_prefix("/dev/zero").audit_rule(rule));
+
+        let (response, _) = respond(&policy, "/dev/null");
+        assert_eq!(response.inner.response, libc::FAN_DENY | libc::FAN_AUDIT);
+        let (response, _) = respond(&policy, "/dev/zero");
+        assert_eq!(
+            response.inner.response,
+            libc::FAN_DENY | libc::FAN_AUDIT | libc::FAN_INFO
+        );
+        assert_eq!(response.audit_rule(), Some(rule));
+        let (response, _) = respond(&policy, "/dev/full");
+        assert_eq!(response.inner.response, libc::FAN_ALLOW);
+    }
+
+    #[test]
+    fn test_cacheable() {
+        // only the access kind and the default verdict hold until the file is modified
+        let policy = Policy::new(Verdict::Allow).rule(Rule::deny().access(Access::Exec));
+        assert!(respond(&policy, "/dev/null").1);
+        let policy = Policy::new(Verdict::Allow).rule(Rule::allow().access(Access::Open));
+        assert!(respond(&policy, "/dev/null").1);
+
+        // a rename or a hard link changes the path
+        let policy = Policy::new(Verdict::Allow).rule(Rule::allow().path_prefix("/dev"));
+        assert!(!respond(&policy, "/dev/null").1);
+        // a rule not matching, but looked at, must not match another process either
+        let policy = Policy::new(Verdict::Allow)
+            .rule(Rule::deny().uid(u32::MAX))
+            .rule(Rule::allow());
+        assert!(!respond(&policy, "/dev/null").1);
+        let policy = Policy::new(Verdict::Allow).rule(Rule::allow().metadata(|_| true));
+        assert!(!respond(&policy, "/dev/null").1);
+        let policy = Policy::new(Verdict::Allow).rule(Rule::allow().audit());
+        assert!(!respond(&policy, "/dev/null").1);
+        // rules after the deciding one don't matter
+        let policy = Policy::new(Verdict::Allow)
+            .rule(Rule::allow())
+            .rule(Rule::deny().exe("/usr/bin/cat"));
+        assert!(respond(&policy, "/dev/null").1);
+    }
+}
@@ -1,3 +1,18 @@
 pub use super::fanotify::Fanotify;
+pub use super::capabilities::Capabilities;
+pub use super::builder::{EventFdAccess, FanotifyBuilder, NotificationClass};
 pub use super::consts::{InitFlags, EventFFlags, MarkFlags, MaskFlags};
-pub use super::messages::{Event, Response, Response as FanotifyResponse};
+pub use super::mark::{MarkOp, MarkSpec, MarkTarget};
+pub use super::messages::{
+    AuditRule, Event, EventBuffer, EventInfo, EventRef, FileHandle, FsError, Fsid, PidFdInfo,
+    Response, Response as FanotifyResponse, Verdict,
+};
+pub use super::fdinfo::{KernelMark, KernelState, MarkObject};
+pub use super::resolver::HandleResolver;
+pub use super::permission::{PermissionHandler, PermissionRequest};
+pub use super::policy::{Policy, Rule};
+pub use super::dispatcher::{DispatcherStats, PermissionDispatcher};
+pub use super::cache::VerdictCache;
+pub use super::shutdown::ShutdownHandle;
+#[cfg(feature = "aio")]
+pub use super::aio::{EventStream, FanotifyReader, FanotifyResponder};
@@ -0,0 +1,423 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::CStr,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    consts::MarkFlags,
+    messages::{FileHandle, Fsid},
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum ResolveError {
+    #[error("no mount registered for filesystem {0:?}")]
+    UnknownFilesystem(Fsid),
+
+    // the object was deleted, or the handle outlived the inode it refers to
+    #[error("stale file handle")]
+    Stale,
+
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Turns file handles from `FAN_REPORT_FID` events back into fds and paths.
+///
+/// `open_by_handle_at(2)` needs an fd on the filesystem a handle belongs to, so the resolver
+/// keeps an fd per fsid. Attach it with [`Fanotify::with_handle_resolver`] and every
+/// path passed to [`Fanotify::mark`] gets registered, or call [`HandleResolver::register`].
+/// Registering is best-effort and happens before the mark is added, so opening the object
+/// doesn't raise an event on the group. A filesystem marked only through fifos, sockets,
+/// devices or symlinks isn't registered, and the failure is logged.
+/// Opening handles requires `CAP_DAC_READ_SEARCH`.
+///
+/// NOTE: registered fds keep the filesystems busy, they can't be unmounted while the resolver lives.
+///
+/// [`Fanotify::with_handle_resolver`]: crate::fanotify::Fanotify::with_handle_resolver
+/// [`Fanotify::mark`]: crate::fanotify::Fanotify::mark
+pub struct HandleResolver {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    mounts: HashMap<Fsid, Arc<OwnedFd>>,
+    // paths with the tick of their last use, the key into `recent`
+    paths: HashMap<FileHandle, (PathBuf, u64)>,
+    // handles by last use, the least recently used one is evicted first
+    recent: BTreeMap<u64, FileHandle>,
+    capacity: usize,
+    tick: u64,
+}
+
+impl HandleResolver {
+    pub const DEFAULT_CACHE_SIZE: usize = 1024;
+
+    pub fn new() -> Self {
+        Self::with_cache_size(Self::DEFAULT_CACHE_SIZE)
+    }
+
+    pub fn with_cache_size(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                mounts: HashMap::new(),
+                paths: HashMap::new(),
+                recent: BTreeMap::new(),
+                capacity,
+                tick: 0,
+            }),
+        }
+    }
+
+    /// Registers the filesystem `path` lives on, returning its fsid.
+    pub fn register<P: AsRef<Path>>(&self, path: P) -> std::io::Result<Fsid> {
+        let path = std::ffi::CString::new(path.as_ref().as_os_str().as_encoded_bytes())?;
+        Ok(self
+            .register_at(libc::AT_FDCWD, Some(&path), MarkFlags::empty())?
+            .0)
+    }
+
+    // register the filesystem of `path` relative to `dirfd`, or of `dirfd` itself without a
+    // path, looked up the way fanotify_mark(2) does with `flags`. Also returns whether it was
+    // registered by this call
+    pub(crate) fn register_at(
+        &self,
+        dirfd: RawFd,
+        path: Option<&CStr>,
+        flags: MarkFlags,
+    ) -> std::io::Result<(Fsid, bool)> {
+        let mut oflags = libc::O_PATH | libc::O_CLOEXEC;
+        if flags.contains(MarkFlags::FAN_MARK_DONT_FOLLOW) {
+            oflags |= libc::O_NOFOLLOW;
+        }
+        if flags.contains(MarkFlags::FAN_MARK_ONLYDIR) {
+            oflags |= libc::O_DIRECTORY;
+        }
+        let object = unsafe {
+            let ret = match path {
+                Some(path) => libc::openat(dirfd, path.as_ptr(), oflags),
+                None => libc::fcntl(dirfd, libc::F_DUPFD_CLOEXEC, 0),
+            };
+            if ret == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            OwnedFd::from_raw_fd(ret)
+        };
+        let fsid = fsid_of(object.as_fd())?;
+        if self.is_registered(fsid) {
+            return Ok((fsid, false));
+        }
+
+        // open_by_handle_at refuses O_PATH fds as mount fd. Only directories and regular files
+        // are reopened, opening fifos blocks and opening devices has side effects
+        let mode = stat_mode(object.as_fd())?;
+        if mode != libc::S_IFDIR && mode != libc::S_IFREG {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "only directories and regular files can be used as mount fd",
+            ));
+        }
+        let proc_path = std::ffi::CString::new(format!("/proc/self/fd/{}", object.as_raw_fd()))?;
+        let fd = unsafe {
+            let flags = libc::O_RDONLY | libc::O_NONBLOCK | libc::O_NOCTTY | libc::O_CLOEXEC;
+            let ret = libc::open(proc_path.as_ptr(), flags);
+            if ret == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            OwnedFd::from_raw_fd(ret)
+        };
+        let mut inner = self.inner.lock().unwrap();
+        let registered = !inner.mounts.contains_key(&fsid);
+        inner.mounts.entry(fsid).or_insert(Arc::new(fd));
+        Ok((fsid, registered))
+    }
+
+    // undo register_at, when the mark it was made for failed
+    pub(crate) fn unregister(&self, fsid: Fsid) {
+        self.inner.lock().unwrap().mounts.remove(&fsid);
+    }
+
+    pub fn is_registered(&self, fsid: Fsid) -> bool {
+        self.inner.lock().unwrap().mounts.contains_key(&fsid)
+    }
+
+    /// Opens the object behind `handle` with `open(2)` style `flags`.
+    pub fn open(&self, handle: &FileHandle, flags: i32) -> Result<OwnedFd, ResolveError> {
+        // not locked during the syscall, so resolving doesn't serialize
+        let mount_fd = self
+            .inner
+            .lock()
+            .unwrap()
+            .mounts
+            .get(&handle.fsid())
+            .cloned();
+        let Some(mount_fd) = mount_fd else {
+            return Err(ResolveError::UnknownFilesystem(handle.fsid()));
+        };
+
+        // struct file_handle { __u32 handle_bytes; int handle_type; unsigned char f_handle[]; }
+        let mut raw = Vec::with_capacity(8 + handle.f_handle().len());
+        raw.extend((handle.f_handle().len() as u32).to_ne_bytes());
+        raw.extend(handle.handle_type().to_ne_bytes());
+        raw.extend(handle.f_handle());
+
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_open_by_handle_at,
+                mount_fd.as_raw_fd(),
+                raw.as_mut_ptr(),
+                flags | libc::O_CLOEXEC,
+            )
+        };
+        if ret == -1 {
+            let error = std::io::Error::last_os_error();
+            if error.raw_os_error() == Some(libc::ESTALE) {
+                return Err(ResolveError::Stale);
+            }
+            return Err(error.into());
+        }
+        Ok(unsafe { OwnedFd::from_raw_fd(ret as RawFd) })
+    }
+
+    /// Opens `handle` as an `O_PATH` fd and looks up its current path, refreshing the cache.
+    pub fn resolve(&self, handle: &FileHandle) -> Result<(OwnedFd, PathBuf), ResolveError> {
+        let fd = self.open(handle, libc::O_PATH)?;
+        let path = std::fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd()))?;
+        self.inner
+            .lock()
+            .unwrap()
+            .insert(handle.clone(), path.clone());
+        Ok((fd, path))
+    }
+
+    /// Like [`HandleResolver::resolve`], but answers from the cache when possible.
+    ///
+    /// A cached path may be outdated if the object was renamed since it was resolved.
+    pub fn resolve_path(&self, handle: &FileHandle) -> Result<PathBuf, ResolveError> {
+        if let Some(path) = self.inner.lock().unwrap().get(handle) {
+            return Ok(path);
+        }
+        Ok(self.resolve(handle)?.1)
+    }
+
+    pub fn invalidate(&self, handle: &FileHandle) {
+        self.inner.lock().unwrap().remove(handle);
+    }
+}
+
+impl Default for HandleResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Inner {
+    fn get(&mut self, handle: &FileHandle) -> Option<PathBuf> {
+        self.tick += 1;
+        let (path, used) = self.paths.get_mut(handle)?;
+        let handle = self.recent.remove(used).unwrap();
+        *used = self.tick;
+        self.recent.insert(self.tick, handle);
+        Some(path.clone())
+    }
+
+    fn insert(&mut self, handle: FileHandle, path: PathBuf) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        if let Some((_, used)) = self.paths.insert(handle.clone(), (path, self.tick)) {
+            self.recent.remove(&used);
+        }
+        self.recent.insert(self.tick, handle);
+        if self.paths.len() > self.capacity {
+            if let Some((_, oldest)) = self.recent.pop_first() {
+                self.paths.remove(&oldest);
+            }
+        }
+    }
+
+    fn remove(&mut self, handle: &FileHandle) {
+        if let Some((_, used)) = self.paths.remove(handle) {
+            self.recent.remove(&used);
+        }
+    }
+}
+
+fn stat_mode(fd: BorrowedFd) -> std::io::Result<libc::mode_t> {
+    let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+    unsafe {
+        if libc::fstat(fd.as_raw_fd(), stat.as_mut_ptr()) == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(stat.assume_init().st_mode & libc::S_IFMT)
+    }
+}
+
+// fsid as fanotify reports it, which is what statfs(2) reports as f_fsid
+fn fsid_of(fd: BorrowedFd) -> std::io::Result<Fsid> {
+    let mut stat = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    let stat = unsafe {
+        if libc::fstatfs(fd.as_raw_fd(), stat.as_mut_ptr()) == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    // fsid_t keeps its two ints private
+    Ok(Fsid(unsafe {
+        std::mem::transmute::<libc::fsid_t, [i32; 2]>(stat.f_fsid)
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        os::fd::OwnedFd,
+        path::PathBuf,
+        sync::{mpsc, Arc},
+        time::Duration,
+    };
+
+    use super::{HandleResolver, ResolveError};
+    use crate::{
+        consts::{EventFFlags, InitFlags, MarkFlags, MaskFlags},
+        fanotify::Fanotify,
+        messages::{FileHandle, Fsid},
+    };
+
+    fn handle(n: u8) -> FileHandle {
+        FileHandle::new(Fsid([1, 2]), 1, vec![n; 8])
+    }
+
+    fn cached(resolver: &HandleResolver, n: u8) -> Option<PathBuf> {
+        resolver.inner.lock().unwrap().get(&handle(n))
+    }
+
+    fn cache(resolver: &HandleResolver, n: u8, path: &str) {
+        let mut inner = resolver.inner.lock().unwrap();
+        inner.insert(handle(n), PathBuf::from(path));
+        assert_eq!(inner.paths.len(), inner.recent.len());
+    }
+
+    #[test]
+    fn test_cache_hit() {
+        let resolver = HandleResolver::new();
+        assert_eq!(cached(&resolver, 1), None);
+        cache(&resolver, 1, "/a");
+        assert_eq!(cached(&resolver, 1), Some(PathBuf::from("/a")));
+        assert_eq!(cached(&resolver, 2), None);
+    }
+
+    #[test]
+    fn test_cache_eviction_order() {
+        let resolver = HandleResolver::with_cache_size(2);
+        cache(&resolver, 1, "/a");
+        cache(&resolver, 2, "/b");
+        // 1 was used last, 2 goes
+        assert!(cached(&resolver, 1).is_some());
+        cache(&resolver, 3, "/c");
+        assert_eq!(cached(&resolver, 2), None);
+        assert!(cached(&resolver, 1).is_some());
+        // 3 was used before 1
+        cache(&resolver, 4, "/d");
+        assert_eq!(cached(&resolver, 3), None);
+        assert!(cached(&resolver, 4).is_some());
+
+        let resolver = HandleResolver::with_cache_size(0);
+        cache(&resolver, 1, "/a");
+        assert_eq!(cached(&resolver, 1), None);
+    }
+
+    #[test]
+    fn test_cache_stale_entries() {
+        let resolver = HandleResolver::new();
+        cache(&resolver, 1, "/old");
+        // resolving again after a rename replaces the path
+        cache(&resolver, 1, "/new");
+        assert_eq!(cached(&resolver, 1), Some(PathBuf::from("/new")));
+
+        resolver.invalidate(&handle(1));
+        assert_eq!(cached(&resolver, 1), None);
+        let inner = resolver.inner.lock().unwrap();
+        assert!(inner.paths.is_empty() && inner.recent.is_empty());
+    }
+
+    #[test]
+    #[ignore = "needs CAP_DAC_READ_SEARCH"]
+    fn test_resolve_stale() {
+        let path = std::env::temp_dir().join(format!("fanotify-stale-{}", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        let resolver = HandleResolver::new();
+        let fsid = resolver.register(&path).unwrap();
+
+        // struct file_handle with room for MAX_HANDLE_SZ bytes
+        let mut raw = [0u8; 8 + 128];
+        raw[..4].copy_from_slice(&128u32.to_ne_bytes());
+        let mut mount_id = 0;
+        let c_path = std::ffi::CString::new(path.as_os_str().as_encoded_bytes()).unwrap();
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_name_to_handle_at,
+                libc::AT_FDCWD,
+                c_path.as_ptr(),
+                raw.as_mut_ptr(),
+                &mut mount_id as *mut i32,
+                0,
+            )
+        };
+        assert_eq!(ret, 0);
+        let len = u32::from_ne_bytes(raw[..4].try_into().unwrap()) as usize;
+        let handle_type = i32::from_ne_bytes(raw[4..8].try_into().unwrap());
+        let handle = FileHandle::new(fsid, handle_type, raw[8..8 + len].to_vec());
+        assert_eq!(resolver.resolve_path(&handle).unwrap(), path);
+        std::fs::remove_file(&path).unwrap();
+
+        // the inode may still be cached after the unlink, a handle with another generation
+        // is what remains once it was reused
+        let mut f_handle = handle.f_handle().to_vec();
+        *f_handle.last_mut().unwrap() ^= 0xff;
+        let stale = FileHandle::new(fsid, handle_type, f_handle);
+        assert!(matches!(resolver.resolve(&stale), Err(ResolveError::Stale)));
+    }
+
+    #[test]
+    fn test_register() {
+        let resolver = HandleResolver::new();
+        let fsid = resolver.register(std::env::temp_dir()).unwrap();
+        assert!(resolver.is_registered(fsid));
+
+        // only directories and regular files can be opened by handle
+        let error = resolver.register("/dev/null").unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    #[ignore = "needs CAP_SYS_ADMIN"]
+    fn test_register_with_permission_mark() {
+        let fan =
+            Fanotify::<OwnedFd>::init(InitFlags::FAN_CLASS_CONTENT, EventFFlags::O_RDONLY).unwrap();
+        let resolver = Arc::new(HandleResolver::new());
+        let fan = fan.with_handle_resolver(resolver.clone());
+        let dir = std::env::temp_dir().join(format!("fanotify-resolver-{}", std::process::id()));
+        std::fs::create_dir(&dir).unwrap();
+
+        // nobody answers the group, opening the marked directory would block
+        let (sender, receiver) = mpsc::channel();
+        let marked = dir.clone();
+        std::thread::spawn(move || {
+            let result = fan.mark(
+                MarkFlags::FAN_MARK_ADD,
+                MaskFlags::FAN_OPEN_PERM | MaskFlags::FAN_ONDIR,
+                None,
+                Some(&marked),
+            );
+            let _ = sender.send(result.is_ok());
+        });
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(true));
+        let fsid = HandleResolver::new().register(&dir).unwrap();
+        assert!(resolver.is_registered(fsid));
+        std::fs::remove_dir(dir).unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Wakes threads blocked reading a group, so synchronous services can stop gracefully.
+///
+/// Attach it with [`Fanotify::with_shutdown_handle`], then `read_events`,
+/// `read_events_timeout` and `read_into` wait for the group and the handle at the same time.
+/// Once [`ShutdownHandle::shutdown`] is called they return no events right away, now and on
+/// every later call. Clones share the same state, and can be moved to other threads or used
+/// from a signal handler thread.
+///
+/// [`Fanotify::with_shutdown_handle`]: crate::fanotify::Fanotify::with_shutdown_handle
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    eventfd: OwnedFd,
+    shutdown: AtomicBool,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> std::io::Result<Self> {
+        let eventfd = unsafe {
+            let ret = libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK);
+            if ret == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            OwnedFd::from_raw_fd(ret)
+        };
+        Ok(Self {
+            inner: Arc::new(Inner {
+                eventfd,
+                shutdown: AtomicBool::new(false),
+            }),
+        })
+    }
+
+    pub fn shutdown(&self) {
+        if self.inner.shutdown.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // never read, so the eventfd stays readable and wakes every waiter
+        let one = 1u64;
+        unsafe {
+            libc::write(
+                self.inner.eventfd.as_raw_fd(),
+                (&one as *const u64).cast(),
+                std::mem::size_of::<u64>(),
+            )
+        };
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.shutdown.load(Ordering::SeqCst)
+    }
+}
+
+impl AsFd for ShutdownHandle {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.eventfd.as_fd()
+    }
+}
+
+/// Waits until `fd` is readable, `shutdown` is triggered or `timeout` passes, and tells
+/// whether `fd` can be read. Waits forever without a timeout.
+pub(crate) fn wait_readable(
+    fd: BorrowedFd,
+    shutdown: Option<&ShutdownHandle>,
+    timeout: Option<Duration>,
+) -> std::io::Result<bool> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut fds = vec![libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    }];
+    if let Some(shutdown) = shutdown {
+        fds.push(libc::pollfd {
+            fd: shutdown.as_fd().as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        });
+    }
+
+    loop {
+        if shutdown.is_some_and(ShutdownHandle::is_shutdown) {
+            return Ok(false);
+        }
+        let timeout_ms = match deadline {
+            // rounded up, so a short timeout doesn't turn into a busy loop
+            Some(deadline) => deadline
+                .saturating_duration_since(Instant::now())
+                .as_nanos()
+                .div_ceil(1_000_000)
+                .min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) };
+        if ret == -1 {
+            let error = std::io::Error::last_os_error();
+            if error.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(error);
+        }
+        if ret == 0 {
+            return Ok(false);
+        }
+        if fds[0].revents != 0 {
+            return Ok(!shutdown.is_some_and(ShutdownHandle::is_shutdown));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        os::fd::AsFd,
+        time::{Duration, Instant},
+    };
+
+    use super::{wait_readable, ShutdownHandle};
+
+    #[test]
+    fn test_shutdown_wakes_waiter() {
+        let handle = ShutdownHandle::new().unwrap();
+        // a second eventfd that never becomes readable stands in for the group
+        let idle = ShutdownHandle::new().unwrap();
+
+        let start = Instant::now();
+        let readable =
+            wait_readable(idle.as_fd(), Some(&handle), Some(Duration::from_millis(20))).unwrap();
+        assert!(!readable);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        let waiter = {
+            let handle = handle.clone();
+            std::thread::spawn(move || wait_readable(idle.as_fd(), Some(&handle), None))
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!handle.is_shutdown());
+        handle.shutdown();
+        assert!(!waiter.join().unwrap().unwrap());
+        assert!(handle.is_shutdown());
+
+        // stays triggered
+        let readable = wait_readable(handle.as_fd(), Some(&handle), None).unwrap();
+        assert!(!readable);
+    }
+}